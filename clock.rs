@@ -0,0 +1,339 @@
+extern mod std;
+
+use cmp::{ Eq, Ord };
+use std::time;
+use std::time::{ Tm, Timespec };
+use std::timer;
+use std::uv;
+
+// Where time-dependent nodes get the current time from and how they wait
+pub enum Clock {
+    RealTime,
+    Virtual(SharedChan<Request>),
+}
+
+enum Request {
+    Now(Chan<u64>),
+    // Wake the sender after this many milliseconds of virtual time. The
+    // sender stops counting as busy until then.
+    Sleep(u64, Chan<()>),
+    // Send on the channel after this many milliseconds of virtual time,
+    // without the sender waiting for it
+    SetTimer(u64, Chan<()>),
+    // Change the number of pulses and tasks still being worked on
+    Busy(int),
+    Advance(u64, Chan<()>),
+    Idle(Chan<()>),
+}
+
+impl Clock {
+    // Milliseconds since the epoch, or since the virtual clock was created
+    fn now_ms(&self) -> u64 {
+        match *self {
+            RealTime => {
+                let now = time::get_time();
+                (now.sec as u64) * 1000 + (now.nsec as u64) / 1000000
+            }
+            Virtual(ref clock) => {
+                let (port, chan) = pipes::stream();
+                clock.send( Now(chan) );
+                port.recv()
+            }
+        }
+    }
+
+    fn now(&self) -> Tm {
+        match *self {
+            RealTime => time::now(),
+            Virtual(_) => {
+                let ms = self.now_ms();
+                time::at_utc(Timespec::new((ms / 1000) as i64, ((ms % 1000) * 1000000) as i32))
+            }
+        }
+    }
+
+    // Unlike now, never jumps when the system clock is set
+    fn instant(&self) -> Instant {
+        match *self {
+            RealTime => Instant { ns: time::precise_time_ns() },
+            Virtual(_) => Instant { ns: self.now_ms() * 1000000 },
+        }
+    }
+
+    fn sleep(&self, ms: uint) {
+        match *self {
+            RealTime => {
+                let iotask = uv::global_loop::get();
+                timer::sleep(iotask, ms);
+            }
+            Virtual(ref clock) => {
+                let (port, chan) = pipes::stream();
+                clock.send( Sleep(ms as u64, chan) );
+                port.recv()
+            }
+        }
+    }
+
+    // Fires the port once ms have passed, dropping it cancels the timer. With
+    // a virtual clock the firing counts as work until its receiver is done.
+    fn timer(&self, ms: uint) -> Port<()> {
+        let (port, chan) = pipes::stream();
+        match *self {
+            RealTime => {
+                do task::spawn {
+                    let iotask = uv::global_loop::get();
+                    timer::sleep(iotask, ms);
+                    chan.try_send(());
+                }
+            }
+            Virtual(ref clock) => clock.send( SetTimer(ms as u64, chan) ),
+        }
+        port
+    }
+
+    // Signal nodes report every pulse they send and finish handling, and
+    // sources report while they are running, so a virtual clock knows when
+    // the graph has settled
+    fn work_started(&self) {
+        match *self {
+            RealTime => (),
+            Virtual(ref clock) => clock.send( Busy(1) ),
+        }
+    }
+
+    fn work_done(&self) {
+        match *self {
+            RealTime => (),
+            Virtual(ref clock) => clock.send( Busy(-1) ),
+        }
+    }
+}
+
+impl Clock: Clone {
+    fn clone(&self) -> Clock {
+        match *self {
+            RealTime => RealTime,
+            Virtual(ref clock) => Virtual(clock.clone()),
+        }
+    }
+}
+
+impl Clock: Owned;
+
+// A point on a clock that only moves forward, for measuring how long
+// something took. Only comparable with instants from the same clock.
+pub struct Instant {
+    priv ns: u64,
+}
+
+impl Instant {
+    fn ns_since(&self, earlier: &Instant) -> u64 {
+        if self.ns > earlier.ns { self.ns - earlier.ns } else { 0 }
+    }
+
+    fn ms_since(&self, earlier: &Instant) -> u64 {
+        self.ns_since(earlier) / 1000000
+    }
+}
+
+impl Instant: Eq {
+    pure fn eq(&self, other: &Instant) -> bool { self.ns == other.ns }
+    pure fn ne(&self, other: &Instant) -> bool { self.ns != other.ns }
+}
+
+impl Instant: Ord {
+    pure fn lt(&self, other: &Instant) -> bool { self.ns < other.ns }
+    pure fn le(&self, other: &Instant) -> bool { self.ns <= other.ns }
+    pure fn ge(&self, other: &Instant) -> bool { self.ns >= other.ns }
+    pure fn gt(&self, other: &Instant) -> bool { self.ns > other.ns }
+}
+
+// A clock that only moves when told to, for testing time-dependent graphs.
+// Time starts at 0 and timers fire in deadline order, each one only after
+// everything the previous one set off has been processed.
+pub struct VirtualClock {
+    priv requests: SharedChan<Request>,
+}
+
+impl VirtualClock {
+    static fn new() -> VirtualClock {
+        let (port, chan) = pipes::stream();
+
+        do task::spawn {
+            run_virtual(port);
+        }
+
+        VirtualClock { requests: SharedChan(chan) }
+    }
+
+    fn clock(&self) -> Clock {
+        Virtual(self.requests.clone())
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.clock().now_ms()
+    }
+
+    // Moves time forward, firing every timer that comes due on the way
+    fn advance_by(&self, ms: uint) {
+        let (port, chan) = pipes::stream();
+        self.requests.send( Advance(ms as u64, chan) );
+        port.recv()
+    }
+
+    // Waits until every pulse in flight has been handled, without moving time
+    fn run_until_idle(&self) {
+        let (port, chan) = pipes::stream();
+        self.requests.send( Idle(chan) );
+        port.recv()
+    }
+}
+
+impl VirtualClock: Clone {
+    fn clone(&self) -> VirtualClock {
+        VirtualClock { requests: self.requests.clone() }
+    }
+}
+
+// Index of the earliest timer due by the deadline, first registered wins ties
+fn next_timer(timers: &[(u64, Chan<()>)], until: u64) -> Option<uint> {
+    let mut next = None;
+    let mut earliest = until;
+    for uint::range(0, timers.len()) |i| {
+        let deadline = match timers[i] { (deadline, _) => deadline };
+        if deadline <= earliest && (next.is_none() || deadline < earliest) {
+            next = Some(i);
+            earliest = deadline;
+        }
+    }
+    next
+}
+
+fn run_virtual(port: Port<Request>) {
+    let mut now = 0u64;
+    let mut busy = 0;
+    let mut timers: ~[(u64, Chan<()>)] = ~[];
+    let mut advancing: Option<(u64, Chan<()>)> = None;
+    let mut waiting: ~[Chan<()>] = ~[];
+
+    loop {
+        match port.try_recv() {
+            Some(Now(reply)) => reply.send(now),
+            Some(Sleep(ms, wake)) => {
+                timers.push( (now + ms, wake) );
+                busy -= 1;
+            }
+            Some(SetTimer(ms, fire)) => timers.push( (now + ms, fire) ),
+            Some(Busy(n)) => busy += n,
+            Some(Advance(ms, reply)) => {
+                if advancing.is_some() { fail ~"VirtualClock is already advancing" }
+                advancing = Some( (now + ms, reply) );
+            }
+            Some(Idle(reply)) => waiting.push(reply),
+            None => break,
+        }
+
+        // Fire one timer at a time, waiting for the graph to settle in between
+        while busy == 0 && advancing.is_some() {
+            let target = match advancing {
+                Some((target, _)) => target,
+                None => fail ~"VirtualClock incorrectly implemented",
+            };
+
+            match next_timer(timers, target) {
+                Some(i) => {
+                    let (deadline, wake) = vec::remove(&mut timers, i);
+                    now = deadline;
+                    if wake.try_send(()) { busy += 1 }
+                }
+                None => {
+                    now = target;
+                    match util::replace(&mut advancing, None) {
+                        Some((_, reply)) => reply.send(()),
+                        None => (),
+                    }
+                }
+            }
+        }
+
+        if busy == 0 {
+            let replies = util::replace(&mut waiting, ~[]);
+            do vec::consume(replies) |_, reply| {
+                reply.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_advance_by() {
+        let clock = VirtualClock::new();
+        assert clock.now_ms() == 0;
+
+        clock.advance_by(25);
+        assert clock.now_ms() == 25;
+    }
+
+    #[test]
+    fn test_sleepers_wake_in_deadline_order() {
+        let clock = VirtualClock::new();
+        let (port, chan) = pipes::stream();
+        let chan = SharedChan(chan);
+
+        for [20u, 10u].each |&ms| {
+            let time = clock.clock();
+            let chan = chan.clone();
+            time.work_started();
+            do task::spawn {
+                time.sleep(ms);
+                chan.send(ms);
+                time.work_done();
+            }
+        }
+
+        clock.advance_by(15);
+        assert port.recv() == 10;
+        assert !port.peek();
+
+        clock.advance_by(10);
+        assert port.recv() == 20;
+        assert clock.now_ms() == 25;
+    }
+
+    #[test]
+    fn test_timer_waits_for_deadline() {
+        let clock = VirtualClock::new();
+        let timer = clock.clock().timer(10);
+
+        clock.advance_by(9);
+        assert !timer.peek();
+
+        // The firing counts as work until it is handled
+        let time = clock.clock();
+        do task::spawn {
+            timer.recv();
+            time.work_done();
+        }
+        clock.advance_by(1);
+        assert clock.now_ms() == 10;
+    }
+
+    #[test]
+    fn test_run_until_idle() {
+        let clock = VirtualClock::new();
+        let time = clock.clock();
+        let (port, chan) = pipes::stream();
+
+        time.work_started();
+        do task::spawn {
+            chan.send(());
+            time.work_done();
+        }
+
+        clock.run_until_idle();
+        assert port.peek();
+        assert clock.now_ms() == 0;
+    }
+}
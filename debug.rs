@@ -0,0 +1,287 @@
+// Time-travel debugging for foldp. A debugged foldp remembers every input it
+// folded and every state it went through, and can go back to any of them.
+
+use trace::NodeTrace;
+use metrics::NodeMetrics;
+
+enum Command<T, U> {
+    History(Chan<~[(Option<T>, U)]>),
+    Rewind(uint, Chan<bool>),
+    StepBack(Chan<bool>),
+    Pause,
+    Resume,
+}
+
+// Handle on a debugged foldp, which can be sent to other tasks
+pub struct Debugger<T, U> {
+    priv commands: SharedChan<Command<T, U>>,
+}
+
+impl<T: Clone Owned, U: Clone Owned> Debugger<T, U> {
+    // Every state so far, with the input that led to it. The first one is the
+    // default, which no input led to.
+    fn history(&self) -> ~[(Option<T>, U)] {
+        let (port, chan) = pipes::stream();
+        if !self.commands.try_send( History(chan) ) { return ~[] }
+        match port.try_recv() {
+            Some(history) => history,
+            None => ~[],
+        }
+    }
+
+    // Goes back to a step of the history, forgetting the ones after it. The
+    // state is emitted again, so everything downstream is recomputed from it.
+    // Returns false if there is no such step or the signal has ended.
+    fn rewind(&self, step: uint) -> bool {
+        let (port, chan) = pipes::stream();
+        if !self.commands.try_send( Rewind(step, chan) ) { return false }
+        port.try_recv().get_default(false)
+    }
+
+    fn step_back(&self) -> bool {
+        let (port, chan) = pipes::stream();
+        if !self.commands.try_send( StepBack(chan) ) { return false }
+        port.try_recv().get_default(false)
+    }
+
+    // Holds updates back until resumed, which freezes everything that waits
+    // on the node
+    fn pause(&self) {
+        self.commands.try_send(Pause);
+    }
+
+    fn resume(&self) {
+        self.commands.try_send(Resume);
+    }
+}
+
+impl<T: Clone Owned, U: Clone Owned> Debugger<T, U>: Clone {
+    fn clone(&self) -> Debugger<T, U> {
+        Debugger { commands: self.commands.clone() }
+    }
+}
+
+// What the node of a debugged foldp keeps track of
+struct Node<T, U> {
+    // Every state with the input that led to it
+    history: ~[(Option<T>, U)],
+    default: U,
+    value: U,
+    chans: ~[Subscriber<U>],
+    clock: ~[Stamp],
+    source: uint,
+    generation: uint,
+    ended: bool,
+    trace: NodeTrace,
+    meter: NodeMetrics,
+}
+
+impl<T: Clone Owned, U: Clone Owned> Node<T, U> {
+    // queued is how many updates are still held back by a pause or on their
+    // way in
+    fn fold(&mut self, f: &fn(T, U) -> U, pulse: Pulse<T>, queued: uint) {
+        match pulse {
+            Change(stamp, x) => {
+                tick(&mut self.clock, stamp);
+                self.meter.received(self.chans.len(), queued);
+                let since = self.trace.received(&x);
+                let started = self.meter.start();
+                let value = util::replace(&mut self.value, self.default.clone());
+                self.value = f(x.clone(), value);
+                self.history.push( (Some(x), self.value.clone()) );
+                self.meter.emitted(started, self.chans.len(), queued);
+                self.trace.emitted(since, &self.value);
+                broadcast(&mut self.chans, stamp, &self.value);
+            }
+            NoChange(stamp) => {
+                tick(&mut self.clock, stamp);
+                broadcast_unchanged(&mut self.chans, stamp);
+            }
+            End => {
+                self.ended = true;
+                broadcast_end(&mut self.chans);
+            }
+            Initial(*) => fail ~"foldp_debug() - Received a second initial value",
+        }
+    }
+
+    // Folds the updates that came in while paused, in order. waiting is how
+    // many more are on their way in.
+    fn replay(&mut self, f: &fn(T, U) -> U, held: ~[Pulse<T>], waiting: uint) {
+        let count = held.len();
+        do vec::consume(held) |i, pulse| {
+            self.fold(f, pulse, count - i - 1 + waiting);
+        }
+    }
+
+    // Drops the history after the step and emits its state again
+    fn rewind(&mut self, step: uint, queued: uint) -> bool {
+        if self.ended || step >= self.history.len() { return false }
+        vec::truncate(&mut self.history, step + 1);
+
+        self.value = match self.history[step] { (_, ref state) => state.clone() };
+        self.generation += 1;
+        let stamp = Stamp { source: self.source, generation: self.generation };
+        tick(&mut self.clock, stamp);
+        self.meter.emitted(None, self.chans.len(), queued);
+        self.trace.emitted(None, &self.value);
+        broadcast(&mut self.chans, stamp, &self.value);
+        true
+    }
+}
+
+// Like foldp, but keeps its history for the debugger. Rewinding stamps the old
+// state as an update of its own, so the node counts as a source as well.
+pub fn foldp_debug<T: Clone Owned, U: Clone Owned>(signal: &Signal<T>, default: U, f: ~fn(T, U) -> U) -> (Signal<U>, Debugger<T, U>) {
+    let (update, chan) = pipes::stream();
+    let (client_port, client_chan) = pipes::stream();
+    let (commands, command_chan) = pipes::stream();
+
+    let graph = signal.graph();
+    let node_graph = graph.clone();
+    let sub = signal.add_pulse_chan(chan, &graph);
+
+    // As with foldp, the current value of the input is folded in
+    let (input_clock, first) = recv_initial(&update);
+    let initial = f(first.clone(), default.clone());
+
+    let source = new_source();
+    let id = graph.register("foldp_debug", [signal.node()]);
+    let trace = graph.trace(id);
+    let meter = graph.meter(id);
+
+    do graph.spawn {
+        let mut upstream = ~[sub];
+
+        let mut node = Node {
+            history: ~[(None, default.clone()), (Some(first), initial.clone())],
+            default: default,
+            value: initial,
+            chans: ~[],
+            clock: join_clocks(&[input_clock, ~[Stamp { source: source, generation: 0 }]]),
+            source: source,
+            generation: 0,
+            ended: false,
+            trace: trace,
+            meter: meter,
+        };
+        let fold = |x, value| f(x, value);
+
+        // Updates that came in while paused
+        let mut held: ~[Pulse<T>] = ~[];
+        let mut paused = false;
+
+        let mut update_open = true;
+        let mut commands_open = true;
+        let mut client_open = true;
+
+        let header0 = PacketHeader();
+        let header1 = PacketHeader();
+        let header2 = PacketHeader();
+
+        let mut ports = ~[update.header(), commands.header(), client_port.header()];
+
+        while update_open || commands_open || client_open {
+            match selecti( ports ) {
+                0 => {
+                    let (pulse, received) = match update.try_recv() {
+                        Some(pulse) => (pulse, true),
+                        None => (End, false),
+                    };
+                    match pulse {
+                        End => {
+                            update_open = false;
+                            upstream = ~[];
+                            ports[0] = &header0;
+                        }
+                        _ => {
+                            for upstream.each |sub| { sub.taken() }
+                            ports[0] = update.header();
+                        }
+                    }
+
+                    if paused {
+                        held.push(pulse);
+                        node.meter.gauges(node.chans.len(), held.len() + backlog(upstream));
+                    } else {
+                        node.fold(fold, pulse, backlog(upstream));
+                    }
+                    if received { node_graph.work_done() }
+                }
+                1 => {
+                    match commands.try_recv() {
+                        Some(History(reply)) => {
+                            reply.try_send( node.history.map(|step| step.clone()) );
+                        }
+                        Some(Rewind(step, reply)) => {
+                            reply.try_send( node.rewind(step, held.len() + backlog(upstream)) );
+                        }
+                        Some(StepBack(reply)) => {
+                            let len = node.history.len();
+                            reply.try_send( len > 1 && node.rewind(len - 2, held.len() + backlog(upstream)) );
+                        }
+                        Some(Pause) => paused = true,
+                        Some(Resume) => {
+                            paused = false;
+                            node.replay(fold, util::replace(&mut held, ~[]), backlog(upstream));
+                        }
+                        // Nobody is debugging anymore, so nobody could resume
+                        None => {
+                            commands_open = false;
+                            paused = false;
+                            node.replay(fold, util::replace(&mut held, ~[]), backlog(upstream));
+                        }
+                    }
+                    ports[1] = if commands_open { commands.header() } else { &header1 };
+                }
+                2 => {
+                    match client_port.try_recv() {
+                        Some(ch) => {
+                            let ch: Subscriber<U> = ch;
+                            if node.ended {
+                                subscribe_ended(ch, node.clock, &node.value);
+                            } else {
+                                subscribe(&mut node.chans, ch, node.clock, &node.value);
+                                node.meter.gauges(node.chans.len(), held.len() + backlog(upstream));
+                            }
+                            ports[2] = client_port.header();
+                        }
+                        None => {
+                            client_open = false;
+                            ports[2] = &header2;
+                        }
+                    }
+                }
+                _ => fail ~"foldp_debug incorrectly implemented",
+            }
+
+            if !client_open && node.chans.is_empty() {
+                drain(&update, &node_graph);
+                break
+            }
+        }
+    }
+
+    let sources = join_sources(&[signal.sources(), ~[source]]);
+    (Signal::new(client_chan, sources, graph, id), Debugger { commands: SharedChan(command_chan) })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_dropping_debugger_resumes() {
+        let (values, chan) = pipes::stream();
+        let numbers = dispatcher(Some(1), || values.try_recv());
+        let (sum, debugger) = foldp_debug(&numbers, 0, |x, sum| x + sum);
+        let total = last(&sum);
+
+        debugger.pause();
+        // Answered after the pause has been
+        debugger.history();
+
+        chan.send(2);
+        util::ignore(chan);
+        util::ignore(debugger);
+        assert total.recv() == 3;
+    }
+}
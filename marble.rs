@@ -0,0 +1,233 @@
+// Marble diagrams for testing signals against a VirtualClock.
+//
+// Every character is one frame of FRAME milliseconds:
+//   'a'    a value during that frame
+//   '-'    nothing happens during that frame
+//   '(ab)' several values during the same frame
+//   '|'    the end of the signal
+//
+// Signals always have a value, so a source diagram has to start with one.
+
+pub const FRAME: uint = 10;
+
+enum Frame {
+    Quiet,
+    Values(~[char]),
+    End,
+}
+
+fn parse(marbles: &str) -> ~[Frame] {
+    let mut frames = ~[];
+    let mut group: Option<~[char]> = None;
+
+    for str::each_char(marbles) |c| {
+        match group {
+            Some(ref mut values) => {
+                if c != ')' { values.push(c); loop }
+            }
+            None => (),
+        }
+
+        match c {
+            '-' => frames.push(Quiet),
+            '|' => frames.push(End),
+            '(' => group = Some(~[]),
+            ')' => match util::replace(&mut group, None) {
+                Some(values) => frames.push( Values(values) ),
+                None => fail fmt!("Unbalanced ')' in marble diagram \"%s\"", marbles),
+            },
+            ' ' => (),
+            c => frames.push( Values(~[c]) ),
+        }
+    }
+
+    if group.is_some() { fail fmt!("Unbalanced '(' in marble diagram \"%s\"", marbles) }
+    frames
+}
+
+// A source that plays back a marble diagram on the graph's clock
+pub fn source(graph: &Graph, marbles: &str) -> Signal<char> {
+    let frames = parse(marbles);
+
+    let initial = if frames.is_empty() { None } else {
+        match frames[0] {
+            Values(ref values) => Some(values[0]),
+            _ => None,
+        }
+    };
+    let initial = match initial {
+        Some(value) => value,
+        None => fail fmt!("Marble diagram \"%s\" needs a value in its first frame", marbles),
+    };
+
+    // Queue up every event with the number of frames to wait before it,
+    // None being the end of the signal
+    let (port, chan) = pipes::stream();
+    let mut last = 0;
+    for uint::range(0, frames.len()) |frame| {
+        match frames[frame] {
+            Values(ref values) => {
+                for uint::range(0, values.len()) |i| {
+                    if frame > 0 || i > 0 {
+                        let value = if values[i] == '|' { None } else { Some(values[i]) };
+                        chan.send( (frame - last, value) );
+                        last = frame;
+                    }
+                }
+            }
+            Quiet => (),
+            End => {
+                chan.send( (frame - last, None) );
+                break
+            }
+        }
+    }
+
+    let clock = graph.clock();
+    do graph.dispatcher(Some(initial)) {
+        match port.try_recv() {
+            Some((wait, value)) => {
+                clock.sleep(wait * FRAME);
+                value
+            }
+            None => None,
+        }
+    }
+}
+
+// Runs the clock for a number of frames, and draws what the signal did
+pub fn record(clock: &VirtualClock, signal: &Signal<char>, frames: uint) -> ~str {
+    let (port, chan) = pipes::stream();
+    let chan = SharedChan(chan);
+
+    let time = signal.graph().clock();
+    let start = clock.now_ms();
+
+    // The end of the signal is recorded as '|'
+    let ended = chan.clone();
+    let end_time = time.clone();
+    let _ender = do on_complete(signal) {
+        ended.send( (end_time.now_ms(), '|') );
+    };
+    let _recorder = do signal.lift |value| {
+        chan.send( (time.now_ms(), value) );
+    };
+
+    // Everything up to the start of the last frame
+    clock.run_until_idle();
+    if frames > 1 { clock.advance_by((frames - 1) * FRAME) }
+
+    let mut buckets = vec::from_fn(frames, |_| ~[]);
+    while port.peek() {
+        let (at, value) = port.recv();
+        let frame = ((at - start) / (FRAME as u64)) as uint;
+        if frame < frames { buckets[frame].push(value) }
+    }
+
+    let mut marbles = ~"";
+    for buckets.each |values| {
+        match values.len() {
+            0 => str::push_char(&mut marbles, '-'),
+            1 => str::push_char(&mut marbles, values[0]),
+            _ => {
+                str::push_char(&mut marbles, '(');
+                for values.each |&value| { str::push_char(&mut marbles, value) }
+                str::push_char(&mut marbles, ')');
+            }
+        }
+    }
+
+    marbles
+}
+
+// Fails unless the signal behaves as the diagram describes
+pub fn expect(clock: &VirtualClock, signal: &Signal<char>, expected: &str) {
+    let frames = parse(expected).len();
+    let actual = record(clock, signal, frames);
+
+    if actual != str::from_slice(expected) {
+        fail fmt!("Expected \"%s\" but signal did \"%s\"", expected, actual);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_foldp() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let input = source(&graph, "a-c-b-d-");
+        let highest = foldp(&input, 'a', |x, max| if x > max { x } else { max });
+        expect(&clock, &highest, "a-c-c-d-");
+    }
+
+    #[test]
+    fn test_filter() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let input = source(&graph, "a-b-c-b-d-");
+        expect(&clock, &filter(&input, 'x', |x| *x != 'b'), "a---c---d-");
+    }
+
+    #[test]
+    fn test_drop_repeats() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let input = source(&graph, "a-a-b-b-a-");
+        expect(&clock, &dropRepeats(&input), "a---b---a-");
+    }
+
+    #[test]
+    fn test_keep_when() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let input = source(&graph, "a-b-c-d-");
+        let gate = source(&graph, "t--f-t--").lift(|x| x == 't');
+        expect(&clock, &keepWhen(&input, &gate, 'x'), "a-b--cd-");
+    }
+
+    #[test]
+    fn test_merge() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let one = source(&graph, "a---c-");
+        let two = source(&graph, "x-b---");
+        expect(&clock, &merge(&one, &two), "a-b-c-");
+    }
+
+    #[test]
+    fn test_merge_prefers_first() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        // Both inputs change on every event, but only the first one is seen
+        let input = source(&graph, "a-b-c-");
+        let renamed = input.lift(|x| if x == 'b' { 'x' } else { x });
+        expect(&clock, &merge(&renamed, &input), "a-x-c-");
+    }
+
+    #[test]
+    fn test_groups() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let input = source(&graph, "a-(bc)-d");
+        expect(&clock, &input, "a-(bc)-d");
+    }
+
+    #[test]
+    fn test_end() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        // Ending is passed on, after the updates before it
+        let input = source(&graph, "a-b-c|-");
+        let highest = foldp(&input, 'a', |x, max| if x > max { x } else { max });
+        expect(&clock, &highest, "a-b-c|-");
+    }
+}
@@ -0,0 +1,290 @@
+// Counters kept for every node of a graph, to find out which ones are slow or
+// busy. Nodes report to a collector task, which hands out snapshots.
+
+extern mod std;
+
+use send_map::linear::LinearMap;
+use private::{ Exclusive, exclusive };
+
+// What a node reports after each update it handles. Counts are added to the
+// node's totals, subscribers and queued replace the last reported ones.
+struct Counts {
+    received: u64,
+    emitted: u64,
+    filtered: u64,
+    busy_ns: u64,
+    subscribers: uint,
+    queued: uint,
+}
+
+enum Request {
+    Node(uint, ~str),
+    Name(uint, ~str),
+    Count(uint, Counts),
+    Snapshot(Chan<~[NodeStats]>),
+}
+
+pub struct NodeStats {
+    node: uint,
+    kind: ~str,
+    name: Option<~str>,
+    // Updates that came in from inputs
+    received: u64,
+    emitted: u64,
+    // Updates that did not change the node. For nodes with several inputs,
+    // events that settled without the node emitting anything.
+    filtered: u64,
+    // Time spent in the node's function
+    busy_ns: u64,
+    subscribers: uint,
+    // Updates waiting for the node: sent to it but not handled yet, held
+    // until its other inputs catch up or held back by a paused debugger.
+    // Timed nodes keep what they hold for later in state of their own, which
+    // is not counted.
+    queue_depth: uint,
+}
+
+pub struct Metrics {
+    priv requests: SharedChan<Request>,
+}
+
+impl Metrics {
+    static fn new() -> Metrics {
+        let (port, chan) = pipes::stream();
+
+        do task::spawn {
+            // Nodes in the order they were built, and where to find each
+            let mut stats: ~[NodeStats] = ~[];
+            let mut index = LinearMap();
+
+            loop {
+                match port.try_recv() {
+                    Some(Node(id, kind)) => {
+                        index.insert(id, stats.len());
+                        stats.push(NodeStats {
+                            node: id,
+                            kind: kind,
+                            name: None,
+                            received: 0,
+                            emitted: 0,
+                            filtered: 0,
+                            busy_ns: 0,
+                            subscribers: 0,
+                            queue_depth: 0,
+                        });
+                    }
+                    Some(Name(id, name)) => match index.find(&id) {
+                        Some(&i) => stats[i].name = Some(name),
+                        None => (),
+                    },
+                    Some(Count(id, counts)) => match index.find(&id) {
+                        Some(&i) => {
+                            stats[i].received += counts.received;
+                            stats[i].emitted += counts.emitted;
+                            stats[i].filtered += counts.filtered;
+                            stats[i].busy_ns += counts.busy_ns;
+                            stats[i].subscribers = counts.subscribers;
+                            stats[i].queue_depth = counts.queued;
+                        }
+                        None => (),
+                    },
+                    Some(Snapshot(reply)) => { reply.try_send( copy stats ); }
+                    None => break,
+                }
+            }
+        }
+
+        Metrics { requests: SharedChan(chan) }
+    }
+
+    fn node(&self, id: uint, kind: &str) {
+        self.requests.send( Node(id, str::from_slice(kind)) );
+    }
+
+    fn name(&self, id: uint, name: &str) {
+        self.requests.send( Name(id, str::from_slice(name)) );
+    }
+
+    // The counters of every node, as of the updates they have reported so far
+    fn snapshot(&self) -> ~[NodeStats] {
+        let (port, chan) = pipes::stream();
+        self.requests.send( Snapshot(chan) );
+        port.recv()
+    }
+
+    // The snapshot in Prometheus' text exposition format
+    fn to_prometheus(&self) -> ~str {
+        let stats = self.snapshot();
+        let mut text = ~"";
+
+        let metric = |text: &mut ~str, name: &str, kind: &str, help: &str, value: &fn(&NodeStats) -> ~str| {
+            *text += fmt!("# HELP %s %s\n# TYPE %s %s\n", name, help, name, kind);
+            for stats.each |node| {
+                *text += fmt!("%s%s %s\n", name, labels(node), value(node));
+            }
+        };
+
+        metric(&mut text, "signal_received_total", "counter", "Updates received by the node",
+               |node| u64::str(node.received));
+        metric(&mut text, "signal_emitted_total", "counter", "Updates emitted by the node",
+               |node| u64::str(node.emitted));
+        metric(&mut text, "signal_filtered_total", "counter", "Updates that did not change the node",
+               |node| u64::str(node.filtered));
+        metric(&mut text, "signal_busy_seconds_total", "counter", "Time spent in the node's function",
+               |node| float::to_str((node.busy_ns as float) / 1e9, 9));
+        metric(&mut text, "signal_subscribers", "gauge", "Subscribers of the node",
+               |node| uint::str(node.subscribers));
+        metric(&mut text, "signal_queue_depth", "gauge", "Updates sent to the node that it has not handled yet",
+               |node| uint::str(node.queue_depth));
+
+        text
+    }
+}
+
+impl Metrics: Clone {
+    fn clone(&self) -> Metrics {
+        Metrics { requests: self.requests.clone() }
+    }
+}
+
+impl Metrics: Owned;
+
+fn labels(node: &NodeStats) -> ~str {
+    let name = match node.name {
+        Some(ref name) => copy *name,
+        None => ~"",
+    };
+    fmt!("{node=\"%s\",kind=\"%s\",name=\"%s\"}", uint::str(node.node), escape(node.kind), escape(name))
+}
+
+// What a node reports through, which does nothing unless its graph has metrics
+pub struct NodeMetrics {
+    priv metrics: Option<Metrics>,
+    priv node: uint,
+}
+
+impl NodeMetrics {
+    static fn new(metrics: Option<Metrics>, node: uint) -> NodeMetrics {
+        NodeMetrics { metrics: metrics, node: node }
+    }
+
+    // Returns when the node started on an update, to time its function by
+    fn start(&self) -> Option<u64> {
+        match self.metrics {
+            Some(_) => Some( std::time::precise_time_ns() ),
+            None => None,
+        }
+    }
+
+    fn received(&self, subscribers: uint, queued: uint) {
+        self.send(1, 0, 0, None, subscribers, queued);
+    }
+
+    fn emitted(&self, started: Option<u64>, subscribers: uint, queued: uint) {
+        self.send(0, 1, 0, started, subscribers, queued);
+    }
+
+    fn filtered(&self, started: Option<u64>, subscribers: uint, queued: uint) {
+        self.send(0, 0, 1, started, subscribers, queued);
+    }
+
+    // Just the gauges, for when the node takes on a subscriber or holds an
+    // update back without handling anything
+    fn gauges(&self, subscribers: uint, queued: uint) {
+        self.send(0, 0, 0, None, subscribers, queued);
+    }
+
+    priv fn send(&self, received: u64, emitted: u64, filtered: u64, started: Option<u64>, subscribers: uint, queued: uint) {
+        match self.metrics {
+            Some(ref metrics) => {
+                let busy_ns = match started {
+                    Some(started) => std::time::precise_time_ns() - started,
+                    None => 0,
+                };
+                metrics.requests.send( Count(self.node, Counts {
+                    received: received,
+                    emitted: emitted,
+                    filtered: filtered,
+                    busy_ns: busy_ns,
+                    subscribers: subscribers,
+                    queued: queued,
+                }) );
+            }
+            None => (),
+        }
+    }
+}
+
+// Pulses sent to a node that it has not taken off its port yet, shared by
+// the node and the subscription it sends them through. Only counted when the
+// receiving graph keeps metrics.
+pub struct Backlog {
+    priv count: Option<Exclusive<uint>>,
+}
+
+impl Backlog {
+    static fn new(counted: bool) -> Backlog {
+        Backlog { count: if counted { Some(exclusive(0u)) } else { None } }
+    }
+
+    fn sent(&self) {
+        match self.count {
+            Some(ref count) => unsafe { do count.with |n| { *n += 1 } },
+            None => (),
+        }
+    }
+
+    fn taken(&self) {
+        match self.count {
+            Some(ref count) => unsafe { do count.with |n| { if *n > 0 { *n -= 1 } } },
+            None => (),
+        }
+    }
+
+    fn len(&self) -> uint {
+        match self.count {
+            Some(ref count) => unsafe { do count.with |n| { *n } },
+            None => 0,
+        }
+    }
+}
+
+impl Backlog: Clone {
+    fn clone(&self) -> Backlog {
+        match self.count {
+            Some(ref count) => Backlog { count: Some(count.clone()) },
+            None => Backlog { count: None },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_merge_counts_filtered() {
+        let metrics = Metrics::new();
+        let (values, numbers) = pipes::stream();
+        let source = Graph::new().with_metrics(&metrics).dispatcher(Some(1), || values.try_recv());
+
+        // 2 gets through to neither input of the merge, 7 to both
+        let big = source.filter(0, |x| *x > 5);
+        let merged = merge(&big, &big.lift(|x| x * 2));
+
+        let (port, chan) = pipes::stream();
+        let _sub = merged.add_chan(chan);
+        port.recv();
+        numbers.send(2);
+        numbers.send(7);
+        assert port.recv() == 7;
+
+        let mut found = false;
+        for metrics.snapshot().each |stats| {
+            if stats.node == merged.node() {
+                assert stats.emitted == 1;
+                assert stats.filtered == 1;
+                found = true;
+            }
+        }
+        assert found;
+    }
+}
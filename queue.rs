@@ -0,0 +1,320 @@
+// Bounded queues between a node and a slow subscriber, and what to do with
+// new values once one is full.
+
+extern mod std;
+
+use std::arc::{ MutexARC, mutex_arc_with_condvars };
+
+pub enum Overflow {
+    // The node waits for the subscriber to make room, which holds up
+    // everything else it sends to as well
+    Block,
+    DropOldest,
+    DropNewest,
+    // Only the newest value is kept, whatever the capacity
+    KeepLatest,
+}
+
+struct State<T> {
+    values: ~[T],
+    // Values lost to overflow
+    dropped: uint,
+    writer_gone: bool,
+    reader_gone: bool,
+}
+
+// The end of the queue a node sends to. Dropping it ends the queue.
+pub struct QueueWriter<T> {
+    priv state: MutexARC<State<T>>,
+    priv capacity: uint,
+    priv overflow: Overflow,
+}
+
+// Returned by Signal::add_bounded. Dropping it unsubscribes.
+pub struct BoundedPort<T> {
+    priv state: MutexARC<State<T>>,
+    priv subscription: Subscription,
+}
+
+pub fn bounded<T: Owned>(capacity: uint, overflow: Overflow) -> (QueueWriter<T>, MutexARC<State<T>>) {
+    if capacity == 0 { fail ~"A bounded queue needs room for at least one value" }
+
+    let state = mutex_arc_with_condvars(State { values: ~[], dropped: 0, writer_gone: false, reader_gone: false }, 1);
+    (QueueWriter { state: state.clone(), capacity: capacity, overflow: overflow }, state)
+}
+
+impl<T: Owned> QueueWriter<T> {
+    // Returns false once the reader has gone away
+    fn push(&self, value: T) -> bool {
+        let capacity = self.capacity;
+        let overflow = self.overflow;
+        let mut value = Some(value);
+
+        unsafe {
+            do self.state.access_cond |state, cond| {
+                match overflow {
+                    Block => while state.values.len() >= capacity && !state.reader_gone { cond.wait() },
+                    _ => (),
+                }
+
+                if !state.reader_gone {
+                    let value = option::unwrap(util::replace(&mut value, None));
+                    match overflow {
+                        KeepLatest => {
+                            state.dropped += state.values.len();
+                            state.values = ~[value];
+                        }
+                        DropNewest if state.values.len() >= capacity => state.dropped += 1,
+                        DropOldest if state.values.len() >= capacity => {
+                            vec::shift(&mut state.values);
+                            state.dropped += 1;
+                            state.values.push(value);
+                        }
+                        _ => state.values.push(value),
+                    }
+                    cond.broadcast();
+                }
+                !state.reader_gone
+            }
+        }
+    }
+}
+
+impl<T: Owned> QueueWriter<T>: Drop {
+    fn finalize(&self) {
+        unsafe {
+            do self.state.access_cond |state, cond| {
+                state.writer_gone = true;
+                cond.broadcast();
+            }
+        }
+    }
+}
+
+impl<T: Owned> BoundedPort<T> {
+    static fn new(state: MutexARC<State<T>>, subscription: Subscription) -> BoundedPort<T> {
+        BoundedPort { state: state, subscription: subscription }
+    }
+
+    // Waits for the next value, None once the signal has ended
+    fn try_recv(&self) -> Option<T> {
+        unsafe {
+            do self.state.access_cond |state, cond| {
+                while state.values.is_empty() && !state.writer_gone { cond.wait() }
+
+                if state.values.is_empty() {
+                    None
+                } else {
+                    let value = vec::shift(&mut state.values);
+                    // A blocked node may be waiting for the room
+                    cond.broadcast();
+                    Some(value)
+                }
+            }
+        }
+    }
+
+    fn recv(&self) -> T {
+        match self.try_recv() {
+            Some(value) => value,
+            None => fail ~"BoundedPort::recv() - The signal has ended",
+        }
+    }
+
+    // The next value if there is one already, without waiting
+    fn poll(&self) -> Option<T> {
+        unsafe {
+            do self.state.access_cond |state, cond| {
+                if state.values.is_empty() {
+                    None
+                } else {
+                    let value = vec::shift(&mut state.values);
+                    cond.broadcast();
+                    Some(value)
+                }
+            }
+        }
+    }
+
+    // How many values were lost to overflow so far
+    fn dropped(&self) -> uint {
+        unsafe {
+            do self.state.access |state| { state.dropped }
+        }
+    }
+}
+
+impl<T: Owned> BoundedPort<T>: Drop {
+    fn finalize(&self) {
+        unsafe {
+            do self.state.access_cond |state, cond| {
+                state.reader_gone = true;
+                cond.broadcast();
+            }
+        }
+    }
+}
+
+struct Cell<T> {
+    // None until the node has sent the current value
+    value: Option<T>,
+    // How many values the node has sent
+    version: uint,
+    writer_gone: bool,
+    reader_gone: bool,
+}
+
+// The end of a latest value subscription a node sends to. Never blocks.
+pub struct LatestWriter<T> {
+    priv cell: MutexARC<Cell<T>>,
+}
+
+// Returned by Signal::add_latest. Only ever holds the newest value, so a
+// slow reader skips the ones it missed. Dropping it unsubscribes.
+pub struct Latest<T> {
+    priv cell: MutexARC<Cell<T>>,
+    // Version of the last value handed out by next
+    priv seen: uint,
+    priv subscription: Subscription,
+}
+
+pub fn latest<T: Owned>() -> (LatestWriter<T>, MutexARC<Cell<T>>) {
+    let cell = mutex_arc_with_condvars(Cell { value: None, version: 0, writer_gone: false, reader_gone: false }, 1);
+    (LatestWriter { cell: cell.clone() }, cell)
+}
+
+impl<T: Owned> LatestWriter<T> {
+    // Returns false once the reader has gone away
+    fn set(&self, value: T) -> bool {
+        let mut value = Some(value);
+        unsafe {
+            do self.cell.access_cond |cell, cond| {
+                if !cell.reader_gone {
+                    cell.value = util::replace(&mut value, None);
+                    cell.version += 1;
+                    cond.broadcast();
+                }
+                !cell.reader_gone
+            }
+        }
+    }
+}
+
+impl<T: Owned> LatestWriter<T>: Drop {
+    fn finalize(&self) {
+        unsafe {
+            do self.cell.access_cond |cell, cond| {
+                cell.writer_gone = true;
+                cond.broadcast();
+            }
+        }
+    }
+}
+
+impl<T: Clone Owned> Latest<T> {
+    static fn new(cell: MutexARC<Cell<T>>, subscription: Subscription) -> Latest<T> {
+        Latest { cell: cell, seen: 0, subscription: subscription }
+    }
+
+    // The newest value, waiting only for the first one to come in
+    fn get(&self) -> T {
+        unsafe {
+            do self.cell.access_cond |cell, cond| {
+                while cell.value.is_none() && !cell.writer_gone { cond.wait() }
+                match cell.value {
+                    Some(ref value) => value.clone(),
+                    None => fail ~"Latest::get() - The signal ended before sending a value",
+                }
+            }
+        }
+    }
+
+    // Waits for a value newer than the last one this returned, skipping any
+    // in between. None once the signal has ended.
+    fn next(&mut self) -> Option<T> {
+        let seen = self.seen;
+        let (version, value) = unsafe {
+            do self.cell.access_cond |cell, cond| {
+                while cell.version <= seen && !cell.writer_gone { cond.wait() }
+                if cell.version > seen {
+                    (cell.version, Some(cell.value.get_ref().clone()))
+                } else {
+                    (cell.version, None)
+                }
+            }
+        };
+        self.seen = version;
+        value
+    }
+}
+
+impl<T: Owned> Latest<T>: Drop {
+    fn finalize(&self) {
+        unsafe {
+            do self.cell.access |cell| {
+                cell.reader_gone = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_bounded_drops_oldest() {
+        let (values, chan) = pipes::stream();
+        let numbers = dispatcher(Some(0), || values.try_recv());
+        let port = numbers.add_bounded(2, DropOldest);
+        assert port.recv() == 0;
+
+        // Nothing is read until all of them are in, so 1 and 2 are dropped
+        for [1, 2, 3, 4].each |&x| { chan.send(x); }
+        while port.dropped() < 2 { task::yield() }
+
+        assert port.recv() == 3;
+        assert port.recv() == 4;
+        assert port.poll().is_none();
+    }
+
+    #[test]
+    fn test_bounded_ends() {
+        let (values, chan) = pipes::stream();
+        let numbers = dispatcher(Some(0), || values.try_recv());
+        let port = numbers.add_bounded(4, Block);
+        chan.send(1);
+
+        util::ignore(chan);
+        assert port.recv() == 0;
+        assert port.recv() == 1;
+        assert port.try_recv().is_none();
+    }
+
+    #[test]
+    fn test_latest_skips_to_newest() {
+        let (values, chan) = pipes::stream();
+        let numbers = dispatcher(Some(1), || values.try_recv());
+        let mut latest = numbers.add_latest();
+        assert latest.next() == Some(1);
+
+        // Wait for 3 to arrive, without reading 2
+        chan.send(2);
+        chan.send(3);
+        while latest.get() != 3 { task::yield() }
+
+        assert latest.next() == Some(3);
+        assert latest.get() == 3;
+    }
+
+    #[test]
+    fn test_latest_ends() {
+        let (values, chan) = pipes::stream();
+        let numbers = dispatcher(Some(1), || values.try_recv());
+        let mut latest = numbers.add_latest();
+        assert latest.next() == Some(1);
+
+        // The dispatcher ends once nothing more can come in
+        util::ignore(chan);
+        assert latest.next().is_none();
+        assert latest.get() == 1;
+    }
+}
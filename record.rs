@@ -0,0 +1,210 @@
+// Recording the updates of a signal to a file, and playing them back.
+//
+// A recording has one update per line: the milliseconds since recording
+// started, a tab, and the serialized value. The first line holds the value
+// the signal had when recording started.
+
+use clock::Instant;
+
+impl int: Serialize {
+    fn serialize(&self) -> ~str { int::str(*self) }
+    static fn deserialize(text: &str) -> Option<int> { int::from_str(text) }
+}
+
+impl uint: Serialize {
+    fn serialize(&self) -> ~str { uint::str(*self) }
+    static fn deserialize(text: &str) -> Option<uint> { uint::from_str(text) }
+}
+
+impl float: Serialize {
+    fn serialize(&self) -> ~str { float::to_str(*self, 17) }
+    static fn deserialize(text: &str) -> Option<float> { float::from_str(text) }
+}
+
+impl bool: Serialize {
+    fn serialize(&self) -> ~str { bool::to_str(*self) }
+    static fn deserialize(text: &str) -> Option<bool> { bool::from_str(text) }
+}
+
+// Escaped like a string of one character
+impl char: Serialize {
+    fn serialize(&self) -> ~str { str::from_char(*self).serialize() }
+    static fn deserialize(text: &str) -> Option<char> {
+        let text: Option<~str> = deserialize(text);
+        match text {
+            Some(text) if str::char_len(text) == 1 => Some(str::char_at(text, 0)),
+            _ => None,
+        }
+    }
+}
+
+// Escapes the characters that would break the line up
+impl ~str: Serialize {
+    fn serialize(&self) -> ~str {
+        let mut text = ~"";
+        for str::each_char(*self) |c| {
+            match c {
+                '\\' => str::push_str(&mut text, "\\\\"),
+                '\n' => str::push_str(&mut text, "\\n"),
+                '\r' => str::push_str(&mut text, "\\r"),
+                '\t' => str::push_str(&mut text, "\\t"),
+                c => str::push_char(&mut text, c),
+            }
+        }
+        text
+    }
+
+    static fn deserialize(text: &str) -> Option<~str> {
+        let mut value = ~"";
+        let mut escaped = false;
+        for str::each_char(text) |c| {
+            if escaped {
+                match c {
+                    '\\' => str::push_char(&mut value, '\\'),
+                    'n' => str::push_char(&mut value, '\n'),
+                    'r' => str::push_char(&mut value, '\r'),
+                    't' => str::push_char(&mut value, '\t'),
+                    _ => return None,
+                }
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else {
+                str::push_char(&mut value, c);
+            }
+        }
+        if escaped { None } else { Some(value) }
+    }
+}
+
+// Writes every update of the signal to the file, with the time it came in.
+// Recording stops when the signal ends or the subscription is disposed of.
+pub fn record_to<T: Serialize Clone Owned>(signal: &Signal<T>, path: &Path) -> Result<Subscription, ~str> {
+    let (update, chan) = pipes::stream();
+    let (opened, opened_chan) = pipes::stream();
+
+    let graph = signal.graph();
+    let node_graph = graph.clone();
+    let sub = signal.add_pulse_chan(chan, &graph);
+    let path = copy *path;
+
+    // Writers can not be sent between tasks, so the file is opened by the recorder
+    do graph.spawn {
+        match io::file_writer(&path, [io::Create, io::Truncate]) {
+            Ok(writer) => {
+                opened_chan.send( Ok(()) );
+
+                let time = node_graph.clock();
+                let start = time.instant();
+                let write = |at: &Instant, value: &T| {
+                    writer.write_str( fmt!("%s\t%s\n", u64::str(at.ms_since(&start)), value.serialize()) );
+                    writer.flush();
+                };
+
+                loop {
+                    match update.try_recv() {
+                        Some(Initial(_, value)) => write(&start, &value),
+                        Some(Change(_, value)) => {
+                            write(&time.instant(), &value);
+                            node_graph.work_done();
+                        }
+                        Some(NoChange(_)) => node_graph.work_done(),
+                        Some(End) => {
+                            node_graph.work_done();
+                            break
+                        }
+                        None => break,
+                    }
+                }
+            }
+            Err(e) => opened_chan.send( Err(e) ),
+        }
+    }
+
+    match opened.recv() {
+        Ok(()) => Ok(sub),
+        Err(e) => Err(fmt!("Could not record to %s: %s", path.to_str(), e)),
+    }
+}
+
+// Plays a recording back with its original timing, ending after the last update
+pub fn replay<T: Serialize Clone Owned>(path: &Path) -> Result<Signal<T>, ~str> {
+    replay_in(&Graph::new(), path, 1.0)
+}
+
+// Like replay, but with the time between updates multiplied by scale, so 0.5
+// plays it back twice as fast
+pub fn replay_in<T: Serialize Clone Owned>(graph: &Graph, path: &Path, scale: float) -> Result<Signal<T>, ~str> {
+    if scale < 0.0 { return Err(~"Recordings can not be replayed backwards") }
+
+    let text = match io::read_whole_file_str(path) {
+        Ok(text) => text,
+        Err(e) => return Err(fmt!("Could not replay %s: %s", path.to_str(), e)),
+    };
+
+    let mut updates = ~[];
+    for str::each_line(text) |line| {
+        match parse_line(line) {
+            Some(update) => updates.push(update),
+            None => return Err(fmt!("Could not replay %s: bad line \"%s\"", path.to_str(), line)),
+        }
+    }
+    if updates.is_empty() { return Err(fmt!("Could not replay %s: it is empty", path.to_str())) }
+
+    // Queue up every update with how long to wait before it
+    let (port, chan) = pipes::stream();
+    let (first_at, initial) = vec::shift(&mut updates);
+    let mut last = first_at;
+    do vec::consume(updates) |_, (at, value)| {
+        let wait = if at > last { ((at - last) as float) * scale } else { 0.0 };
+        chan.send( (wait as uint, value) );
+        last = at;
+    }
+
+    let clock = graph.clock();
+    let signal = do graph.dispatcher(Some(initial)) {
+        match port.try_recv() {
+            Some((wait, value)) => {
+                clock.sleep(wait);
+                Some(value)
+            }
+            None => None,
+        }
+    };
+    Ok(signal)
+}
+
+fn parse_line<T: Serialize>(line: &str) -> Option<(u64, T)> {
+    match str::find_char(line, '\t') {
+        Some(tab) => {
+            let at = u64::from_str(str::slice(line, 0, tab));
+            let value: Option<T> = deserialize(str::slice(line, tab + 1, line.len()));
+            match (at, value) {
+                (Some(at), Some(value)) => Some((at, value)),
+                _ => None,
+            }
+        }
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_char_round_trip() {
+        for ['a', ' ', '\n', '\r', '\t', '\\'].each |&c| {
+            let line = fmt!("10\t%s", c.serialize());
+            assert str::find_char(line, '\n').is_none();
+
+            let parsed: Option<(u64, char)> = parse_line(line);
+            assert parsed == Some((10, c));
+        }
+    }
+
+    #[test]
+    fn test_str_round_trip() {
+        let text = ~"one\ttwo\nthree\\";
+        let parsed: Option<(u64, ~str)> = parse_line(fmt!("0\t%s", text.serialize()));
+        assert parsed == Some((0, text));
+    }
+}
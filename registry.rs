@@ -0,0 +1,99 @@
+// Keeps track of the nodes of a graph and how they are wired, for debugging.
+// Nodes are registered when they are built and stay listed for as long as
+// the registry is around, so it is best left off outside of debugging.
+
+enum Request {
+    Add(NodeInfo),
+    Name(uint, ~str),
+    Nodes(Chan<~[NodeInfo]>),
+}
+
+pub struct NodeInfo {
+    id: uint,
+    // The function that built the node, such as "lift" or "merge2"
+    kind: ~str,
+    // Given with Signal::named
+    name: Option<~str>,
+    // Ids of the nodes it subscribes to
+    inputs: ~[uint],
+}
+
+pub struct Registry {
+    priv requests: SharedChan<Request>,
+}
+
+impl Registry {
+    static fn new() -> Registry {
+        let (port, chan) = pipes::stream();
+
+        do task::spawn {
+            let mut nodes = ~[];
+            loop {
+                match port.try_recv() {
+                    Some(Add(node)) => nodes.push(node),
+                    Some(Name(id, name)) => {
+                        for uint::range(0, nodes.len()) |i| {
+                            if nodes[i].id == id { nodes[i].name = Some(copy name) }
+                        }
+                    }
+                    Some(Nodes(reply)) => { reply.try_send( copy nodes ); }
+                    None => break,
+                }
+            }
+        }
+
+        Registry { requests: SharedChan(chan) }
+    }
+
+    fn add(&self, id: uint, kind: &str, inputs: &[uint]) {
+        self.requests.send( Add(NodeInfo { id: id, kind: str::from_slice(kind), name: None, inputs: vec::from_slice(inputs) }) );
+    }
+
+    fn name(&self, id: uint, name: &str) {
+        self.requests.send( Name(id, str::from_slice(name)) );
+    }
+
+    // Every node registered so far, in the order they were built
+    fn nodes(&self) -> ~[NodeInfo] {
+        let (port, chan) = pipes::stream();
+        self.requests.send( Nodes(chan) );
+        port.recv()
+    }
+
+    // The graph in Graphviz's DOT format, with data flowing along the edges
+    fn to_dot(&self) -> ~str {
+        let nodes = self.nodes();
+
+        // Ids are shared with sources and other graphs, so nodes are numbered in
+        // the order they were built
+        let index = |id: uint| vec::position(nodes, |node| node.id == id);
+
+        let mut dot = ~"digraph signals {\n";
+        for nodes.eachi |i, node| {
+            let label = match node.name {
+                Some(ref name) => fmt!("%s\n(%s)", *name, node.kind),
+                None => copy node.kind,
+            };
+            dot += fmt!("    n%u [label=\"%s\"];\n", i, escape(label));
+        }
+        for nodes.eachi |i, node| {
+            for node.inputs.each |&input| {
+                match index(input) {
+                    Some(j) => dot += fmt!("    n%u -> n%u;\n", j, i),
+                    // Built before the registry was switched on
+                    None => dot += fmt!("    u%u [label=\"?\", style=dashed];\n    u%u -> n%u;\n", input, input, i),
+                }
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+impl Registry: Clone {
+    fn clone(&self) -> Registry {
+        Registry { requests: self.requests.clone() }
+    }
+}
+
+impl Registry: Owned;
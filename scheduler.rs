@@ -0,0 +1,67 @@
+use task::{ SchedMode, SingleThreaded, ManualThreads };
+
+// Decides which threads the tasks backing signal nodes run on
+pub enum Scheduler {
+    // Every node gets a scheduler of its own with this mode
+    PerNode(SchedMode),
+    // Nodes are spawned as tasks of one shared scheduler
+    Shared(SharedChan<~fn()>),
+}
+
+impl Scheduler {
+    // What every node used before schedulers were configurable
+    static fn default() -> Scheduler {
+        PerNode(ManualThreads(4))
+    }
+
+    // Runs all nodes on a single thread, one after the other
+    static fn event_loop() -> Scheduler {
+        shared(SingleThreaded)
+    }
+
+    // Runs all nodes on a fixed number of threads
+    static fn thread_pool(threads: uint) -> Scheduler {
+        if threads == 0 { fail ~"A thread pool needs at least one thread" }
+        shared(ManualThreads(threads))
+    }
+
+    static fn thread_per_node() -> Scheduler {
+        PerNode(SingleThreaded)
+    }
+
+    fn spawn(&self, f: ~fn()) {
+        match *self {
+            PerNode(mode) => task::spawn_sched(mode, f),
+            Shared(ref launcher) => launcher.send(f),
+        }
+    }
+}
+
+impl Scheduler: Clone {
+    fn clone(&self) -> Scheduler {
+        match *self {
+            PerNode(mode) => PerNode(mode),
+            Shared(ref launcher) => Shared(launcher.clone()),
+        }
+    }
+}
+
+impl Scheduler: Owned;
+
+// Starts a task on a new scheduler that spawns every function it is sent.
+// Tasks spawned from it stay on its scheduler.
+fn shared(mode: SchedMode) -> Scheduler {
+    let (port, chan) = pipes::stream();
+
+    do task::spawn_sched(mode) {
+        loop {
+            let f: Option<~fn()> = port.try_recv();
+            match f {
+                Some(f) => task::spawn(f),
+                None => break,
+            }
+        }
+    }
+
+    Shared(SharedChan(chan))
+}
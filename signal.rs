@@ -3,9 +3,27 @@
 use cmp::Eq;
 use either::*;
 use pipes::*;
+use private::{ Exclusive, exclusive };
+use private::global::global_data_clone_create;
 
 extern mod std; // Needing this here might be a bug
 pub mod time;
+pub mod scheduler;
+pub mod clock;
+pub mod marble;
+pub mod record;
+pub mod debug;
+pub mod registry;
+pub mod trace;
+pub mod metrics;
+pub mod queue;
+
+use scheduler::Scheduler;
+use clock::{ Clock, RealTime, VirtualClock };
+use registry::Registry;
+use trace::{ Tracer, NodeTrace };
+use metrics::{ Metrics, NodeMetrics, Backlog };
+use queue::{ Overflow, QueueWriter, BoundedPort, LatestWriter, Latest };
 
 pub trait Clone {
     fn clone(&self) -> self;
@@ -17,17 +35,526 @@ impl<T: Copy> T: Clone {
     }
 }
 
+// Turns values into a single line of text and back, so signals can be
+// recorded and replayed
+pub trait Serialize {
+    fn serialize(&self) -> ~str;
+    static fn deserialize(text: &str) -> Option<self>;
+}
+
+// Identifies an event: the source it started from and how many events that
+// source had produced at the time. Every node forwards exactly one pulse per
+// stamp it receives, so nodes with several inputs can wait until all of them
+// have caught up before emitting (no glitches in diamond shaped graphs).
+pub struct Stamp {
+    source: uint,
+    generation: uint,
+}
+
+impl Stamp: Eq {
+    pure fn eq(&self, other: &Stamp) -> bool {
+        self.source == other.source && self.generation == other.generation
+    }
+
+    pure fn ne(&self, other: &Stamp) -> bool {
+        !self.eq(other)
+    }
+}
+
+pub enum Pulse<T> {
+    // Current value on subscription, with the last generation seen from each source
+    Initial(~[Stamp], T),
+    Change(Stamp, T),
+    NoChange(Stamp),
+    // The signal will not change anymore
+    End,
+}
+
+// A downstream client of a node, along with the port its Subscription
+// handle closes when it goes away
+struct Subscriber<T> {
+    sink: Sink<T>,
+    alive: Port<()>,
+}
+
+enum Sink<T> {
+    // Plain values, as handed to add_chan
+    Values(Chan<T>),
+    // Other nodes, which need the stamps as well. Every pulse sent is
+    // reported to the clock of the receiving node's graph, and counted in
+    // its backlog until the node takes it.
+    Pulses(Chan<Pulse<T>>, Clock, Backlog),
+    // Plain values through a bounded queue, as handed out by add_bounded
+    Queued(QueueWriter<T>),
+    // Only the newest value, as handed out by add_latest
+    Newest(LatestWriter<T>),
+}
+
+// These return false once the subscriber has been disposed or its port dropped
+impl<T: Clone Owned> Subscriber<T> {
+    fn send_initial(&self, clock: &[Stamp], value: &T) -> bool {
+        if self.alive.peek() { return false }
+        match self.sink {
+            Values(ref ch) => ch.try_send( value.clone() ),
+            Pulses(ref ch, _, _) => ch.try_send( Initial(vec::from_slice(clock), value.clone()) ),
+            Queued(ref queue) => queue.push( value.clone() ),
+            Newest(ref cell) => cell.set( value.clone() ),
+        }
+    }
+
+    fn send_change(&self, stamp: Stamp, value: &T) -> bool {
+        if self.alive.peek() { return false }
+        match self.sink {
+            Values(ref ch) => ch.try_send( value.clone() ),
+            Pulses(ref ch, ref clock, ref backlog) => {
+                clock.work_started();
+                backlog.sent();
+                if ch.try_send( Change(stamp, value.clone()) ) { return true }
+                backlog.taken();
+                clock.work_done();
+                false
+            }
+            Queued(ref queue) => queue.push( value.clone() ),
+            Newest(ref cell) => cell.set( value.clone() ),
+        }
+    }
+
+    fn send_unchanged(&self, stamp: Stamp) -> bool {
+        if self.alive.peek() { return false }
+        match self.sink {
+            Values(_) | Queued(_) | Newest(_) => true,
+            Pulses(ref ch, ref clock, ref backlog) => {
+                clock.work_started();
+                backlog.sent();
+                if ch.try_send( NoChange(stamp) ) { return true }
+                backlog.taken();
+                clock.work_done();
+                false
+            }
+        }
+    }
+
+    // Plain value subscribers find out when their port closes instead
+    fn send_end(&self) {
+        if self.alive.peek() { return }
+        match self.sink {
+            Values(_) | Queued(_) | Newest(_) => (),
+            Pulses(ref ch, ref clock, _) => {
+                clock.work_started();
+                if !ch.try_send(End) { clock.work_done() }
+            }
+        }
+    }
+}
+
+// Handle returned by add_chan. Dropping it (or calling dispose) removes the
+// subscriber from the upstream node, which finds out through the alive port.
+pub struct Subscription {
+    priv alive: Option<Chan<()>>,
+    priv backlog: Backlog,
+}
+
+impl Subscription {
+    fn dispose(&mut self) {
+        match util::replace(&mut self.alive, None) {
+            Some(alive) => { alive.try_send(()); }
+            None => (),
+        }
+    }
+
+    pure fn is_disposed(&self) -> bool {
+        self.alive.is_none()
+    }
+
+    // Changes and unchanged pulses sent through an add_pulse_chan
+    // subscription that the receiver has not taken yet. Only counted when
+    // the receiver's graph keeps metrics, 0 otherwise.
+    fn backlog(&self) -> uint {
+        self.backlog.len()
+    }
+
+    // Called by the receiver for each Change or NoChange it takes
+    fn taken(&self) {
+        self.backlog.taken()
+    }
+}
+
+impl Subscription: Drop {
+    fn finalize(&self) {
+        match self.alive {
+            Some(ref alive) => { alive.try_send(()); }
+            None => (),
+        }
+    }
+}
+
+// Settings shared by the nodes of a signal graph. Nodes derived from other
+// signals are built against the graph of their (first) input.
+pub struct Graph {
+    priv scheduler: Scheduler,
+    priv clock: Clock,
+    priv registry: Option<Registry>,
+    priv tracer: Option<Tracer>,
+    priv metrics: Option<Metrics>,
+}
+
+impl Graph {
+    static fn new() -> Graph {
+        Graph::on(Scheduler::default())
+    }
+
+    // Starts out with the tracer installed with Tracer::install, if any
+    static fn on(scheduler: Scheduler) -> Graph {
+        Graph { scheduler: scheduler, clock: RealTime, registry: None, tracer: trace::global(), metrics: None }
+    }
+
+    // Runs every node on one thread, with time only moving when the
+    // VirtualClock is advanced
+    static fn virtual_time(clock: &VirtualClock) -> Graph {
+        Graph::on(Scheduler::event_loop()).with_clock(clock.clock())
+    }
+
+    fn with_scheduler(&self, scheduler: Scheduler) -> Graph {
+        let mut graph = self.clone();
+        graph.scheduler = scheduler;
+        graph
+    }
+
+    fn with_clock(&self, clock: Clock) -> Graph {
+        let mut graph = self.clone();
+        graph.clock = clock;
+        graph
+    }
+
+    // Nodes built against the returned graph, or derived from them, are
+    // listed in a registry of their own
+    fn with_registry(&self) -> Graph {
+        let mut graph = self.clone();
+        graph.registry = Some(Registry::new());
+        graph
+    }
+
+    // Nodes built against the returned graph, or derived from them, pass
+    // every value they handle on to the tracer
+    fn with_tracer(&self, tracer: &Tracer) -> Graph {
+        let mut graph = self.clone();
+        graph.tracer = Some(tracer.clone());
+        graph
+    }
+
+    // Nodes built against the returned graph, or derived from them, keep
+    // count of what they do in the metrics
+    fn with_metrics(&self, metrics: &Metrics) -> Graph {
+        let mut graph = self.clone();
+        graph.metrics = Some(metrics.clone());
+        graph
+    }
+
+    fn clock(&self) -> Clock {
+        self.clock.clone()
+    }
+
+    fn registry(&self) -> Option<Registry> {
+        match self.registry {
+            Some(ref registry) => Some(registry.clone()),
+            None => None,
+        }
+    }
+
+    fn tracer(&self) -> Option<Tracer> {
+        match self.tracer {
+            Some(ref tracer) => Some(tracer.clone()),
+            None => None,
+        }
+    }
+
+    fn metrics(&self) -> Option<Metrics> {
+        match self.metrics {
+            Some(ref metrics) => Some(metrics.clone()),
+            None => None,
+        }
+    }
+
+    // Gives a new node its id, listing it if the graph keeps a registry
+    fn register(&self, kind: &str, inputs: &[uint]) -> uint {
+        let id = new_source();
+        match self.registry {
+            Some(ref registry) => registry.add(id, kind, inputs),
+            None => (),
+        }
+        match self.tracer {
+            Some(ref tracer) => tracer.node(id, kind),
+            None => (),
+        }
+        match self.metrics {
+            Some(ref metrics) => metrics.node(id, kind),
+            None => (),
+        }
+        id
+    }
+
+    fn name(&self, node: uint, name: &str) {
+        match self.registry {
+            Some(ref registry) => registry.name(node, name),
+            None => (),
+        }
+        match self.tracer {
+            Some(ref tracer) => tracer.name(node, name),
+            None => (),
+        }
+        match self.metrics {
+            Some(ref metrics) => metrics.name(node, name),
+            None => (),
+        }
+    }
+
+    fn trace(&self, node: uint) -> NodeTrace {
+        NodeTrace::new(self.tracer(), node, self.clock())
+    }
+
+    fn meter(&self, node: uint) -> NodeMetrics {
+        NodeMetrics::new(self.metrics(), node)
+    }
+
+    fn spawn(&self, f: ~fn()) {
+        self.scheduler.spawn(f)
+    }
+
+    // Called by nodes once they are done with a pulse
+    fn work_done(&self) {
+        self.clock.work_done()
+    }
+
+    fn constant<T: Clone Owned>(&self, value: T) -> Signal<T> {
+        let (port, chan) = pipes::stream();
+
+        do self.spawn {
+            loop {
+                let client: Option<Subscriber<T>> = port.try_recv();
+                match client {
+                    Some(ch) => subscribe_ended(ch, ~[], &value),
+                    None => break,
+                }
+            }
+        }
+
+        // Never changes, so it does not count as a source and ends right away
+        Signal::new(chan, ~[], self.clone(), self.register("constant", []))
+    }
+
+    fn dispatcher<T: Clone Owned>(&self, default: Option<T>, f: ~fn() -> Option<T>) -> Signal<T> {
+        match self.try_dispatcher(default, f) {
+            Ok(signal) => signal,
+            Err(e) => fail e,
+        }
+    }
+
+    // Like dispatcher, but returns an error rather than failing when there is
+    // no default and f ends without producing a value
+    fn try_dispatcher<T: Clone Owned>(&self, default: Option<T>, f: ~fn() -> Option<T>) -> Result<Signal<T>, ~str> {
+        let (client_port, client_chan) = pipes::stream();
+        let (value_port, value_chan) = pipes::stream();
+
+        let source = new_source();
+        let time = self.clock();
+
+        // The producer counts as busy whenever it is not sleeping on the clock
+        time.work_started();
+
+        do self.spawn {
+            let mut generation = 0;
+            loop {
+                match f() {
+                    Some(value) => {
+                        generation += 1;
+                        let stamp = Stamp { source: source, generation: generation };
+                        time.work_started();
+                        // The node has shut down, so nobody is listening anymore
+                        if !value_chan.try_send( Change(stamp, value) ) {
+                            time.work_done();
+                            break
+                        }
+                    }
+                    None => break,
+                }
+            }
+            time.work_done();
+        }
+
+        let (clock, initial) = match default {
+            Some(value) => (~[Stamp { source: source, generation: 0 }], value),
+            None => match value_port.try_recv() {
+                Some(Change(stamp, value)) => {
+                    self.work_done();
+                    (~[stamp], value)
+                }
+                _ => return Err(~"dispatcher() - The source ended without a value"),
+            },
+        };
+
+        let node = self.register("dispatcher", []);
+        signal_loop(self, node, initial, clock, value_port, client_port, ~[], |x, _| x, |_| true);
+
+        Ok(Signal::new(client_chan, ~[source], self.clone(), node))
+    }
+
+    // A source that changes whenever a value is sent to it, from any task
+    fn input<T: Clone Owned>(&self, default: T) -> Input<T> {
+        let (client_port, client_chan) = pipes::stream();
+        let (value_port, value_chan) = pipes::stream();
+        let (input_port, input_chan) = pipes::stream();
+
+        let source = new_source();
+        let time = self.clock();
+
+        // Unlike a dispatcher, nothing is busy while waiting for a value. The
+        // sender counts as busy from the moment it sends one instead.
+        do self.spawn {
+            let mut generation = 0;
+            loop {
+                match input_port.try_recv() {
+                    Some(value) => {
+                        generation += 1;
+                        let stamp = Stamp { source: source, generation: generation };
+                        if !value_chan.try_send( Change(stamp, value) ) {
+                            time.work_done();
+                            break
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        let node = self.register("input", []);
+        let clock = ~[Stamp { source: source, generation: 0 }];
+        signal_loop(self, node, default, clock, value_port, client_port, ~[], |x, _| x, |_| true);
+
+        Input {
+            values: SharedChan(input_chan),
+            clock: self.clock(),
+            signal: Signal::new(client_chan, ~[source], self.clone(), node),
+        }
+    }
+}
+
+impl Graph: Clone {
+    fn clone(&self) -> Graph {
+        Graph {
+            scheduler: self.scheduler.clone(),
+            clock: self.clock.clone(),
+            registry: self.registry(),
+            tracer: self.tracer(),
+            metrics: self.metrics(),
+        }
+    }
+}
+
+impl Graph: Owned;
+
 pub struct Signal<T: Clone Owned> {
-    priv update: SharedChan< Chan<T> >,
+    priv update: SharedChan< Subscriber<T> >,
+    // Ids of the sources (dispatchers) this signal is derived from
+    priv sources: ~[uint],
+    priv graph: Graph,
+    // Id of the node behind the signal
+    priv node: uint,
 }
 
 impl<T: Clone Owned> Signal<T> {
-    static fn new(ch: Chan<Chan<T>>) -> Signal<T> {
-        Signal { update: SharedChan(ch) }
+    static fn new(ch: Chan<Subscriber<T>>, sources: ~[uint], graph: Graph, node: uint) -> Signal<T> {
+        Signal { update: SharedChan(ch), sources: sources, graph: graph, node: node }
+    }
+
+    pure fn sources(&self) -> ~[uint] {
+        copy self.sources
+    }
+
+    pure fn node(&self) -> uint {
+        self.node
+    }
+
+    // Names the node behind the signal in the registry and in traces
+    fn named(&self, name: &str) -> Signal<T> {
+        self.graph.name(self.node, name);
+        self.clone()
+    }
+
+    fn graph(&self) -> Graph {
+        self.graph.clone()
+    }
+
+    // The same signal, but nodes derived from it run on another scheduler
+    fn on(&self, scheduler: Scheduler) -> Signal<T> {
+        Signal {
+            update: self.update.clone(),
+            sources: copy self.sources,
+            graph: self.graph.with_scheduler(scheduler),
+            node: self.node,
+        }
+    }
+
+    fn add_chan(&self, ch: Chan<T>) -> Subscription {
+        self.subscribe(Values(ch), Backlog::new(false))
+    }
+
+    // Like add_chan, but holds at most capacity values for a reader that falls
+    // behind, and deals with the others as overflow says
+    fn add_bounded(&self, capacity: uint, overflow: Overflow) -> BoundedPort<T> {
+        let (writer, state) = queue::bounded(capacity, overflow);
+        BoundedPort::new(state, self.subscribe(Queued(writer), Backlog::new(false)))
+    }
+
+    // Subscribes to the signal as a value that changes over time rather than
+    // a stream of updates. The node never waits on the reader, which only
+    // sees the newest value whenever it looks.
+    fn add_latest(&self) -> Latest<T> {
+        let (writer, cell) = queue::latest();
+        Latest::new(cell, self.subscribe(Newest(writer), Backlog::new(false)))
+    }
+
+    // Like add_chan, but keeps the stamps so the receiver can take part in
+    // glitch-free propagation. The first pulse is always Initial, and the
+    // receiver must call work_done on its graph after handling each other one,
+    // and taken on the subscription when it is a Change or NoChange.
+    fn add_pulse_chan(&self, ch: Chan<Pulse<T>>, graph: &Graph) -> Subscription {
+        let backlog = Backlog::new(graph.metrics().is_some());
+        self.subscribe(Pulses(ch, graph.clock(), backlog.clone()), backlog)
     }
 
-    fn add_chan(&self, ch: Chan<T>) {
-        self.update.send(ch)
+    priv fn subscribe(&self, sink: Sink<T>, backlog: Backlog) -> Subscription {
+        let (alive_port, alive_chan) = pipes::stream();
+        self.update.send( Subscriber { sink: sink, alive: alive_port } );
+        Subscription { alive: Some(alive_chan), backlog: backlog }
+    }
+
+    // The value the signal has right now
+    fn current(&self) -> T {
+        let (port, chan) = pipes::stream();
+        let _sub = self.add_chan(chan);
+        port.recv()
+    }
+
+    // Waits for the signal to change, None if it ends first
+    fn next(&self) -> Option<T> {
+        let (port, chan) = pipes::stream();
+        let _sub = self.add_chan(chan);
+        port.recv();
+        port.try_recv()
+    }
+
+    // Every change from here on, until the signal ends or f returns false:
+    //   for signal.each |value| { ... }
+    fn each(&self, f: &fn(T) -> bool) {
+        let (port, chan) = pipes::stream();
+        let _sub = self.add_chan(chan);
+        port.recv();
+        loop {
+            match port.try_recv() {
+                Some(value) => if !f(value) { break },
+                None => break,
+            }
+        }
     }
 
     fn lift<U: Clone Owned>(&self, f: ~fn(T) -> U) -> Signal<U> {
@@ -41,220 +568,817 @@ impl<T: Clone Owned> Signal<T> {
     fn foldp<U: Clone Owned>(&self, default: U, f: ~fn(T, U) -> U) -> Signal<U> {
         foldp(self, default, f)
     }
+
+    fn flat_map<U: Clone Owned>(&self, f: ~fn(T) -> Signal<U>) -> Signal<U> {
+        flat_map(self, f)
+    }
 }
 
 impl <T: Clone Owned> Signal<T>: Clone {
     fn clone(&self) -> Signal<T> {
-        Signal { update: self.update.clone() }
+        Signal {
+            update: self.update.clone(),
+            sources: copy self.sources,
+            graph: self.graph.clone(),
+            node: self.node,
+        }
     }
 }
 
 impl <T: Clone Owned> Signal<T>: Owned;
 
-// Work around issue #4718, also nice to have custom spawner
+// Handle for pushing values into a graph from imperative code. The signal
+// ends once every copy of the handle has been dropped.
+pub struct Input<T: Clone Owned> {
+    priv values: SharedChan<T>,
+    priv clock: Clock,
+    priv signal: Signal<T>,
+}
+
+impl<T: Clone Owned> Input<T> {
+    // Returns false once the signal has shut down
+    fn send(&self, value: T) -> bool {
+        self.clock.work_started();
+        if self.values.try_send(value) { return true }
+        self.clock.work_done();
+        false
+    }
+
+    fn signal(&self) -> Signal<T> {
+        self.signal.clone()
+    }
+}
+
+impl<T: Clone Owned> Input<T>: Clone {
+    fn clone(&self) -> Input<T> {
+        Input { values: self.values.clone(), clock: self.clock.clone(), signal: self.signal.clone() }
+    }
+}
+
+impl<T: Clone Owned> Input<T>: Owned;
+
+// Key of the counter new_source hands out ids from
+fn source_counter(_: Exclusive<uint>) {}
+
+// Ids of sources and nodes, unique within the process and handed out in the
+// order they were asked for, so they are the same from one run to the next
+pub fn new_source() -> uint {
+    unsafe {
+        let counter = global_data_clone_create(source_counter, || ~exclusive(0u));
+        do counter.with |next| {
+            *next += 1;
+            *next
+        }
+    }
+}
+
+// Quotes text for the registry's DOT output and the Prometheus labels of
+// metrics, which escape the same three characters
+pub fn escape(text: &str) -> ~str {
+    let mut escaped = ~"";
+    for str::each_char(text) |c| {
+        match c {
+            '"' => str::push_str(&mut escaped, "\\\""),
+            '\\' => str::push_str(&mut escaped, "\\\\"),
+            '\n' => str::push_str(&mut escaped, "\\n"),
+            c => str::push_char(&mut escaped, c),
+        }
+    }
+    escaped
+}
+
+// Last generation of a source reflected in a clock, 0 if none yet
+pub pure fn generation(clock: &[Stamp], source: uint) -> uint {
+    for clock.each |stamp| {
+        if stamp.source == source { return stamp.generation }
+    }
+    0
+}
+
+pub fn tick(clock: &mut ~[Stamp], stamp: Stamp) {
+    for uint::range(0, clock.len()) |i| {
+        if clock[i].source == stamp.source {
+            if clock[i].generation < stamp.generation { clock[i] = stamp }
+            return
+        }
+    }
+    clock.push(stamp);
+}
+
+pub fn join_clocks(clocks: &[~[Stamp]]) -> ~[Stamp] {
+    let mut joined = ~[];
+    for clocks.each |clock| {
+        for clock.each |&stamp| {
+            tick(&mut joined, stamp);
+        }
+    }
+    joined
+}
+
+pub fn join_sources(sources: &[~[uint]]) -> ~[uint] {
+    let mut joined = ~[];
+    for sources.each |ids| {
+        for ids.each |&id| {
+            if !vec::contains(joined, &id) { joined.push(id) }
+        }
+    }
+    joined
+}
+
+// Reads the Initial pulse every subscription starts with
+#[inline(always)]
+pub fn recv_initial<T: Clone Owned>(port: &Port<Pulse<T>>) -> (~[Stamp], T) {
+    match port.recv() {
+        Initial(clock, value) => (clock, value),
+        _ => fail ~"Signal did not start with its initial value",
+    }
+}
+
+// How many pulses are waiting in the queues of a node
+fn queued<T>(queues: &[~[(Stamp, Option<T>)]]) -> uint {
+    queues.foldl(0, |&n, queue| n + queue.len())
+}
+
+// Removes the pulse for a stamp from an input queue, returning its value if
+// that input changed
+fn take<T>(queue: &mut ~[(Stamp, Option<T>)], stamp: Stamp) -> Option<T> {
+    match vec::position(*queue, |&(s, _)| s == stamp) {
+        Some(i) => {
+            let (_, value) = vec::remove(queue, i);
+            value
+        }
+        None => None,
+    }
+}
+
+// Keeps track of which inputs of a node still owe a pulse for a stamp
+struct Barrier {
+    deps: ~[~[uint]],
+    clocks: ~[~[Stamp]],
+    pending: ~[~[Stamp]],
+    ended: ~[bool],
+}
+
+impl Barrier {
+    static fn new(deps: ~[~[uint]], clocks: ~[~[Stamp]]) -> Barrier {
+        let pending = vec::from_fn(deps.len(), |_| ~[]);
+        let ended = vec::from_elem(deps.len(), false);
+        Barrier { deps: deps, clocks: clocks, pending: pending, ended: ended }
+    }
+
+    fn owes(&self, input: uint, stamp: Stamp) -> bool {
+        !self.ended[input] &&
+            vec::contains(self.deps[input], &stamp.source) &&
+            generation(self.clocks[input], stamp.source) < stamp.generation
+    }
+
+    fn arrive(&mut self, input: uint, stamp: Stamp) {
+        self.pending[input].push(stamp);
+    }
+
+    // An input that has ended will not deliver anything else
+    fn end(&mut self, input: uint) {
+        self.ended[input] = true;
+    }
+
+    fn is_settled(&self, stamp: Stamp) -> bool {
+        for uint::range(0, self.pending.len()) |i| {
+            if self.owes(i, stamp) && !vec::contains(self.pending[i], &stamp) {
+                return false
+            }
+        }
+        true
+    }
+
+    // Returns a stamp every dependent input has delivered, if there is one
+    fn settle(&mut self) -> Option<Stamp> {
+        let mut ready = None;
+        for self.pending.each |stamps| {
+            for stamps.each |&stamp| {
+                if ready.is_none() && self.is_settled(stamp) {
+                    ready = Some(stamp);
+                }
+            }
+        }
+
+        match ready {
+            Some(stamp) => {
+                for uint::range(0, self.pending.len()) |i| {
+                    if self.owes(i, stamp) {
+                        tick(&mut self.clocks[i], stamp);
+                    }
+                    match vec::position_elem(self.pending[i], &stamp) {
+                        Some(j) => { vec::remove(&mut self.pending[i], j); }
+                        None => (),
+                    }
+                }
+            }
+            None => (),
+        }
+        ready
+    }
+}
+
+// Sends a value to every subscriber, dropping the ones that have gone away
 #[inline(always)]
-fn spawn(f: ~fn()) {
-    use task::{ spawn, spawn_sched, SchedMode };
-    use task::{ SingleThreaded,ThreadPerCore, ThreadPerTask, ManualThreads, PlatformThread };
+fn broadcast<T: Clone Owned>(chans: &mut ~[Subscriber<T>], stamp: Stamp, value: &T) {
+    let old = util::replace(chans, ~[]);
+    do vec::consume(old) |_, sub| {
+        if sub.send_change(stamp, value) {
+            chans.push(sub);
+        }
+    }
+}
+
+// Lets downstream nodes know this node had nothing to say about a stamp
+#[inline(always)]
+fn broadcast_unchanged<T: Clone Owned>(chans: &mut ~[Subscriber<T>], stamp: Stamp) {
+    let old = util::replace(chans, ~[]);
+    do vec::consume(old) |_, sub| {
+        if sub.send_unchanged(stamp) {
+            chans.push(sub);
+        }
+    }
+}
+
+// Also drops the subscribers that went away since the node last sent
+// anything, so a node that rarely changes does not pile them up
+#[inline(always)]
+fn subscribe<T: Clone Owned>(chans: &mut ~[Subscriber<T>], sub: Subscriber<T>, clock: &[Stamp], value: &T) {
+    let old = util::replace(chans, ~[]);
+    do vec::consume(old) |_, other| {
+        if !other.alive.peek() { chans.push(other) }
+    }
+
+    if sub.send_initial(clock, value) {
+        chans.push(sub);
+    }
+}
+
+// Subscribing to a signal that has ended still gets its last value
+#[inline(always)]
+fn subscribe_ended<T: Clone Owned>(sub: Subscriber<T>, clock: &[Stamp], value: &T) {
+    if sub.send_initial(clock, value) {
+        sub.send_end();
+    }
+}
+
+// Tells every subscriber the signal has ended and lets go of them
+#[inline(always)]
+fn broadcast_end<T: Clone Owned>(chans: &mut ~[Subscriber<T>]) {
+    let old = util::replace(chans, ~[]);
+    do vec::consume(old) |_, sub| {
+        sub.send_end();
+    }
+}
+
+// Throws away whatever an input sent after its node stopped listening
+fn drain<T: Owned>(port: &Port<Pulse<T>>, graph: &Graph) {
+    while port.peek() {
+        match port.try_recv() {
+            Some(_) => graph.work_done(),
+            None => break,
+        }
+    }
+}
+
+// What a node does with an update
+pub enum Step<U> {
+    Emit(U),
+    Skip(U),
+    // Emit, then end the signal
+    EmitLast(U),
+    // End the signal without emitting
+    Stop(U),
+}
 
-    let scheduler = ManualThreads(4);
-    spawn_sched(scheduler, f);
+// Pulses sent to a node through its upstream subscriptions and not taken yet
+fn backlog(upstream: &[Subscription]) -> uint {
+    let mut queued = 0;
+    for upstream.each |sub| { queued += sub.backlog() }
+    queued
 }
 
 #[inline(always)]
 pub fn signal_loop<T: Clone Owned, U: Clone Owned>(
+    graph: &Graph,
+    node: uint,
     default: U,
-    update: Port<T>,
-    new_client: Port<Chan<U>>,
+    clock: ~[Stamp],
+    update: Port<Pulse<T>>,
+    new_client: Port<Subscriber<U>>,
+    upstream: ~[Subscription],
     process: ~fn(T, U) -> U,
     filter: ~fn(&T) -> bool)
 {
-    do spawn {
-        let mut chans: ~[Chan<U>] = ~[];
+    do step_loop(graph, node, default, clock, update, new_client, upstream) |x, value| {
+        if filter(&x) { Emit(process(x, value)) } else { Skip(value) }
+    }
+}
+
+#[inline(always)]
+pub fn step_loop<T: Clone Owned, U: Clone Owned>(
+    graph: &Graph,
+    node: uint,
+    default: U,
+    clock: ~[Stamp],
+    update: Port<Pulse<T>>,
+    new_client: Port<Subscriber<U>>,
+    upstream: ~[Subscription],
+    step: ~fn(T, U) -> Step<U>)
+{
+    do state_loop(graph, node, default, (), clock, update, new_client, upstream) |x, value, _| {
+        step(x, value)
+    }
+}
+
+// Like step_loop, but step also keeps state of its own, which unlike the
+// value is never sent to subscribers
+pub fn state_loop<T: Clone Owned, U: Clone Owned, S: Owned>(
+    graph: &Graph,
+    node: uint,
+    default: U,
+    state: S,
+    clock: ~[Stamp],
+    update: Port<Pulse<T>>,
+    new_client: Port<Subscriber<U>>,
+    upstream: ~[Subscription],
+    step: ~fn(T, U, &mut S) -> Step<U>)
+{
+    let node_graph = graph.clone();
+    let trace = graph.trace(node);
+    let meter = graph.meter(node);
+
+    do graph.spawn {
+        // Keeps the upstream subscriptions alive for as long as this node listens
+        let mut upstream = upstream;
+
+        let mut chans: ~[Subscriber<U>] = ~[];
         let mut value = default.clone();
+        let mut state = state;
+        let mut clock = clock;
         let mut update_open = true;
         let mut client_open = true;
-        
+
+        // Every pulse is forwarded, as a NoChange if nothing came of it.
+        // Also returns whether the signal goes on. With a single input there
+        // is nothing to wait for, so the only pulses queued are the ones
+        // still on their way in.
+        let handle_pulse = |pulse: Pulse<T>, value: U, chans: &mut ~[Subscriber<U>], queued: uint| -> (U, bool) {
+            match pulse {
+                Change(stamp, tmp) => {
+                    tick(&mut clock, stamp);
+                    meter.received(chans.len(), queued);
+                    let since = trace.received(&tmp);
+                    let started = meter.start();
+                    match step(tmp, value, &mut state) {
+                        Emit(value) => {
+                            meter.emitted(started, chans.len(), queued);
+                            trace.emitted(since, &value);
+                            broadcast(chans, stamp, &value);
+                            (value, true)
+                        }
+                        Skip(value) => {
+                            meter.filtered(started, chans.len(), queued);
+                            trace.filtered(since, &value);
+                            broadcast_unchanged(chans, stamp);
+                            (value, true)
+                        }
+                        EmitLast(value) => {
+                            meter.emitted(started, chans.len(), queued);
+                            trace.emitted(since, &value);
+                            broadcast(chans, stamp, &value);
+                            (value, false)
+                        }
+                        Stop(value) => {
+                            meter.filtered(started, chans.len(), queued);
+                            trace.filtered(since, &value);
+                            broadcast_unchanged(chans, stamp);
+                            (value, false)
+                        }
+                    }
+                }
+                NoChange(stamp) => {
+                    tick(&mut clock, stamp);
+                    broadcast_unchanged(chans, stamp);
+                    (value, true)
+                }
+                End => (value, false),
+                Initial(*) => fail ~"Signal sent its initial value twice",
+            }
+        };
+
         loop {
-            if client_open && update_open {
-                match select2i(&update, &new_client) {
+            if update_open && (client_open || !chans.is_empty()) {
+                let ready = if client_open {
+                    select2i(&update, &new_client)
+                } else {
+                    Left(())
+                };
+
+                match ready {
                     Left(()) => {
-                        let opt_tmp = update.try_recv();
-                        match opt_tmp {
-                            Some(tmp) => {
-                                if filter(&tmp) {
-                                    value = process(tmp, value);
-                                    for chans.each |c| {
-                                        c.send( value.clone() );
-                                    }
+                        let open = match update.try_recv() {
+                            Some(pulse) => {
+                                match pulse {
+                                    Change(*) | NoChange(*) => for upstream.each |sub| { sub.taken() },
+                                    _ => (),
                                 }
-                            },
-                            None => update_open = false,
+                                let queued = backlog(upstream);
+                                let (new_value, open) = handle_pulse(pulse, value, &mut chans, queued);
+                                value = new_value;
+                                node_graph.work_done();
+                                open
+                            }
+                            None => false,
+                        };
+
+                        if !open {
+                            update_open = false;
+                            upstream = ~[];
+                            drain(&update, &node_graph);
+                            broadcast_end(&mut chans);
                         }
                     },
                     Right(()) => {
-                        let opt_ch: Option<Chan<U>> = new_client.try_recv();
+                        let opt_ch: Option<Subscriber<U>> = new_client.try_recv();
                         match opt_ch {
                             Some(ch) => {
-                                ch.send( value.clone() );
-                                chans.push(ch);
-                            },
-                            None => client_open = false,
-                        }
-                    },
-                }
-            } else if update_open {
-                let opt_tmp = update.try_recv();
-                match opt_tmp {
-                    Some(tmp) => {
-                        if filter(&tmp) {
-                            value = process(tmp, value);
-                            for chans.each |c| {
-                                c.send( value.clone() );
+                                subscribe(&mut chans, ch, clock, &value);
+                                meter.gauges(chans.len(), backlog(upstream));
                             }
+                            None => client_open = false,
                         }
                     },
-                    None => update_open = false,
                 }
-            } else if client_open {
-                let opt_ch: Option<Chan<U>> = new_client.try_recv();
+            } else if client_open && !update_open {
+                // The signal has ended, but its last value can still be read
+                let opt_ch: Option<Subscriber<U>> = new_client.try_recv();
                 match opt_ch {
-                    Some(ch) => {
-                        ch.send( value.clone() );
-                        chans.push(ch);
-                    },
+                    Some(ch) => subscribe_ended(ch, clock, &value),
                     None => client_open = false,
                 }
             } else {
+                // Nobody can subscribe anymore and the last client has left.
+                // Whatever the input still sent has to be accounted for.
+                drain(&update, &node_graph);
                 break
             }
         }
     }
 }
 
-#[inline(always)]
-pub fn lift<T: Clone Owned, U: Clone Owned>(signal: &Signal<T>, f: ~fn(T) -> U) -> Signal<U> {
+// What a timed node hears about
+pub enum Event<T> {
+    Input(T),
+    // The timer went off
+    Alarm,
+    // The input ended
+    Finished,
+}
+
+// What a timed node wants done with its timer
+pub enum Timer {
+    Keep,
+    After(uint),
+    Cancel,
+}
+
+// A node that emits on its own schedule rather than in step with its input,
+// so it stamps its updates as a source of its own. f hears about every change
+// of the input and every time the timer goes off, and pushes whatever the node
+// should emit. initial turns the input's current value into the node's, and
+// can start the timer. The node ends once its input has and no timer is set.
+pub fn timed_loop<T: Clone Owned, U: Clone Owned, S: Owned>(
+    kind: &str, signal: &Signal<T>, state: S, initial: &fn(&T, &mut S) -> (U, Timer), f: ~fn(Event<T>, &mut S, &mut ~[U]) -> Timer) -> Signal<U>
+{
     let (update, chan) = pipes::stream();
     let (client_port, client_chan) = pipes::stream();
 
-    signal.add_chan(chan);
+    let graph = signal.graph();
+    let node_graph = graph.clone();
+    let sub = signal.add_pulse_chan(chan, &graph);
+
+    let time = graph.clock();
+    let mut state = state;
+
+    let (_, first) = recv_initial(&update);
+    let (first, timer) = match initial(&first, &mut state) {
+        (first, After(ms)) => (first, Some( time.timer(ms) )),
+        (first, _) => (first, None),
+    };
+
+    let source = new_source();
+    let node = graph.register(kind, [signal.node()]);
+    let trace = graph.trace(node);
+    let meter = graph.meter(node);
+
+    do graph.spawn {
+        let mut upstream = ~[sub];
+
+        let mut timer = timer;
+        let mut state = state;
+
+        let mut chans: ~[Subscriber<U>] = ~[];
+        let mut value = first;
+        let mut generation = 0;
+        let mut clock = ~[Stamp { source: source, generation: 0 }];
+
+        let mut update_open = true;
+        let mut client_open = true;
+        let mut ended = false;
+
+        let header0 = PacketHeader();
+        let header1 = PacketHeader();
+        let header2 = PacketHeader();
+
+        let mut ports = ~[update.header(), &header1, client_port.header()];
+        if timer.is_some() { ports[1] = timer.get_ref().header() }
+
+        while update_open || timer.is_some() || client_open {
+            let mut received = false;
+            let mut emits = ~[];
+            let mut action = Keep;
+            // When the input that led to the emits came in, if it was traced,
+            // and when f started on it, if it was metered. Updates f keeps in
+            // its state for later are not counted as queued.
+            let mut since = None;
+            let mut started = None;
+
+            match selecti( ports ) {
+                0 => {
+                    match update.try_recv() {
+                        Some(Change(_, x)) => {
+                            for upstream.each |sub| { sub.taken() }
+                            meter.received(chans.len(), backlog(upstream));
+                            since = trace.received(&x);
+                            started = meter.start();
+                            action = f(Input(x), &mut state, &mut emits);
+                            // Whatever it leads to later goes out on a timer
+                            if emits.is_empty() {
+                                meter.filtered(started, chans.len(), backlog(upstream));
+                                trace.filtered(since, &value);
+                            }
+                            ports[0] = update.header();
+                            received = true;
+                        }
+                        // Only its own updates are stamped, so the input's are of no interest
+                        Some(NoChange(_)) => {
+                            for upstream.each |sub| { sub.taken() }
+                            ports[0] = update.header();
+                            received = true;
+                        }
+                        Some(End) => {
+                            update_open = false;
+                            upstream = ~[];
+                            ports[0] = &header0;
+                            action = f(Finished, &mut state, &mut emits);
+                            received = true;
+                        }
+                        Some(Initial(*)) => fail ~"timed_loop() - Received a second initial value",
+                        None => {
+                            update_open = false;
+                            upstream = ~[];
+                            ports[0] = &header0;
+                            action = f(Finished, &mut state, &mut emits);
+                        }
+                    }
+                }
+                1 => {
+                    let fired = match timer {
+                        Some(ref port) => port.try_recv().is_some(),
+                        None => false,
+                    };
+                    timer = None;
+                    ports[1] = &header1;
+                    if fired {
+                        started = meter.start();
+                        action = f(Alarm, &mut state, &mut emits);
+                        received = true;
+                    }
+                }
+                2 => {
+                    match client_port.try_recv() {
+                        Some(ch) => {
+                            let ch: Subscriber<U> = ch;
+                            if ended {
+                                subscribe_ended(ch, clock, &value);
+                            } else {
+                                subscribe(&mut chans, ch, clock, &value);
+                                meter.gauges(chans.len(), backlog(upstream));
+                            }
+                            ports[2] = client_port.header();
+                        }
+                        None => {
+                            client_open = false;
+                            ports[2] = &header2;
+                        }
+                    }
+                }
+                _ => fail ~"timed_loop incorrectly implemented",
+            }
+
+            match action {
+                Keep => (),
+                After(_) | Cancel => {
+                    // A timer that already went off still has to be accounted for
+                    match util::replace(&mut timer, None) {
+                        Some(port) => if port.peek() {
+                            port.recv();
+                            node_graph.work_done();
+                        },
+                        None => (),
+                    }
+                    ports[1] = &header1;
+                }
+            }
+            match action {
+                After(ms) => {
+                    timer = Some( time.timer(ms) );
+                    ports[1] = timer.get_ref().header();
+                }
+                _ => (),
+            }
+
+            do vec::consume(emits) |_, x| {
+                generation += 1;
+                let stamp = Stamp { source: source, generation: generation };
+                tick(&mut clock, stamp);
+                value = x;
+                // The time spent in f counts towards the first update only
+                meter.emitted(util::replace(&mut started, None), chans.len(), backlog(upstream));
+                trace.emitted(since, &value);
+                broadcast(&mut chans, stamp, &value);
+            }
+
+            if !update_open && timer.is_none() && !ended {
+                ended = true;
+                broadcast_end(&mut chans);
+            }
+
+            if received { node_graph.work_done() }
 
-    let initial = f( update.recv() );
-    signal_loop(initial, update, client_port, |x, _| f(x), |_| true);
+            if !client_open && chans.is_empty() {
+                drain(&update, &node_graph);
+                // As does a timer that went off
+                match timer {
+                    Some(ref port) => if port.peek() {
+                        port.recv();
+                        node_graph.work_done();
+                    },
+                    None => (),
+                }
+                break
+            }
+        }
+    }
 
-    Signal::new(client_chan)
+    Signal::new(client_chan, ~[source], graph, node)
 }
 
 #[inline(always)]
-pub fn filter_lift<T: Clone Owned, U: Clone Owned>(signal: &Signal<T>, initial: U, filter: ~fn(&T) -> bool, process: ~fn(T, U) -> U) -> Signal<U> {
+pub fn lift<T: Clone Owned, U: Clone Owned>(signal: &Signal<T>, f: ~fn(T) -> U) -> Signal<U> {
     let (update, chan) = pipes::stream();
     let (client_port, client_chan) = pipes::stream();
 
-    signal.add_chan(chan);
+    let graph = signal.graph();
+    let sub = signal.add_pulse_chan(chan, &graph);
 
-    signal_loop(initial.clone(), update, client_port, process, filter);
+    let (clock, first) = recv_initial(&update);
+    let initial = f(first);
+    let node = graph.register("lift", [signal.node()]);
+    signal_loop(&graph, node, initial, clock, update, client_port, ~[sub], |x, _| f(x), |_| true);
 
-    Signal::new(client_chan)
+    Signal::new(client_chan, signal.sources(), graph, node)
 }
 
-pub fn lift2<T: Clone Owned, U: Clone Owned, V: Clone Owned>(s1: &Signal<T>, s2: &Signal<U>, f: ~fn(T, U) -> V) -> Signal<V> {
-    let signal = merge2(s1, s2);
-    let signal = signal.lift(|(x, y)| f(x, y));
-    signal
+#[inline(always)]
+pub fn filter_lift<T: Clone Owned, U: Clone Owned>(signal: &Signal<T>, initial: U, filter: ~fn(&T) -> bool, process: ~fn(T, U) -> U) -> Signal<U> {
+    filter_lift_as("filter_lift", signal, initial, filter, process)
 }
 
-pub fn lift3<A: Clone Owned, B: Clone Owned, C: Clone Owned, V: Clone Owned>(
-    s1: &Signal<A>, s2: &Signal<B>, s3: &Signal<C>, f: ~fn(A, B, C) -> V) -> Signal<V>
+// filter_lift, listed in the registry as another kind of node
+fn filter_lift_as<T: Clone Owned, U: Clone Owned>(
+    kind: &str, signal: &Signal<T>, initial: U, filter: ~fn(&T) -> bool, process: ~fn(T, U) -> U) -> Signal<U>
 {
-    merge3(s1, s2, s3).lift(|(x, y, z)| f(x, y, z))
-}
+    let (update, chan) = pipes::stream();
+    let (client_port, client_chan) = pipes::stream();
 
-pub fn lift4<A: Clone Owned, B: Clone Owned, C: Clone Owned, D: Clone Owned, V: Clone Owned>(
-    s1: &Signal<A>, s2: &Signal<B>, s3: &Signal<C>, s4: &Signal<D>, f: ~fn(A, B, C, D) -> V) -> Signal<V>
-{
-    let m1 = &merge2(s1, s2);
-    let m2 = &merge2(s3, s4);
-    merge2(m1, m2).lift(|((a, b), (c, d))| f(a, b, c, d))
-}
+    let graph = signal.graph();
+    let sub = signal.add_pulse_chan(chan, &graph);
 
-pub fn lift5<A: Clone Owned, B: Clone Owned, C: Clone Owned, D: Clone Owned, E: Clone Owned, V: Clone Owned>(
-    s1: &Signal<A>, s2: &Signal<B>, s3: &Signal<C>, s4: &Signal<D>, s5: &Signal<E>, f: ~fn(A, B, C, D, E) -> V) -> Signal<V>
-{
-    let m1 = &merge2(s1, s2);
-    let m2 = &merge3(s3, s4, s5);
-    merge2(m1, m2).lift(|((a, b), (c, d, e))| f(a, b, c, d, e))
-}
+    // The current value of the input is processed like any other update
+    let (clock, first) = recv_initial(&update);
+    let initial = if filter(&first) { process(first, initial) } else { initial };
 
-pub fn lift6<A: Clone Owned, B: Clone Owned, C: Clone Owned, D: Clone Owned, E: Clone Owned, F: Clone Owned, V: Clone Owned>(
-    s1: &Signal<A>, s2: &Signal<B>, s3: &Signal<C>, s4: &Signal<D>, s5: &Signal<E>, s6: &Signal<F>, op: ~fn(A, B, C, D, E, F) -> V) -> Signal<V>
-{
-    let m1 = &merge3(s1, s2, s3);
-    let m2 = &merge3(s4, s5, s6);
-    merge2(m1, m2).lift(|((a, b, c), (d, e, f))| op(a, b, c, d, e, f))
-}
+    let node = graph.register(kind, [signal.node()]);
+    signal_loop(&graph, node, initial, clock, update, client_port, ~[sub], process, filter);
 
-#[inline(always)]
-pub fn constant<T: Clone Owned>(value: T) -> Signal<T> {
-    let (port, chan) = pipes::stream();
+    Signal::new(client_chan, signal.sources(), graph, node)
+}
 
-    do spawn {
-        loop {
-            let client: Option<Chan<T>> = port.try_recv();
-            match client {
-                Some(ch) => ch.send( value.clone() ),
-                None => break,
+// Nests the signals into pairs with merge2, ending with the last signal
+macro_rules! merge_nested (
+    ($signal:expr) => ( ($signal).clone() );
+    ($signal:expr, $($rest:expr),+) => ( merge2($signal, &merge_nested!($($rest),+)) );
+)
+
+// Calls f with the values of a pair nested by merge_nested, one name per value
+macro_rules! apply_nested (
+    ($f:expr, $nested:expr, ($($done:ident),*), $last:ident) => ( $f($($done,)* $nested) );
+    ($f:expr, $nested:expr, ($($done:ident),*), $x:ident, $($rest:ident),+) => ({
+        let ($x, rest) = $nested;
+        apply_nested!($f, rest, ($($done,)* $x), $($rest),+)
+    });
+)
+
+// Defines a liftN for as many signals as it is given
+macro_rules! define_lift (
+    ($name:ident, $($signal:ident: $T:ident),+) => (
+        pub fn $name<$($T: Clone Owned),+, V: Clone Owned>($($signal: &Signal<$T>),+, f: ~fn($($T),+) -> V) -> Signal<V> {
+            do merge_nested!($($signal),+).lift |nested| {
+                apply_nested!(f, nested, (), $($signal),+)
             }
         }
-    }
+    )
+)
+
+define_lift!(lift2, s1: A, s2: B)
+define_lift!(lift3, s1: A, s2: B, s3: C)
+define_lift!(lift4, s1: A, s2: B, s3: C, s4: D)
+define_lift!(lift5, s1: A, s2: B, s3: C, s4: D, s5: E)
+define_lift!(lift6, s1: A, s2: B, s3: C, s4: D, s5: E, s6: F)
+define_lift!(lift7, s1: A, s2: B, s3: C, s4: D, s5: E, s6: F, s7: G)
+define_lift!(lift8, s1: A, s2: B, s3: C, s4: D, s5: E, s6: F, s7: G, s8: H)
 
-    Signal::new(chan)
+#[inline(always)]
+pub fn constant<T: Clone Owned>(value: T) -> Signal<T> {
+    Graph::new().constant(value)
 }
 
 #[inline(always)]
 pub fn dispatcher<T: Clone Owned>(default: Option<T>, f: ~fn() -> Option<T>) -> Signal<T> {
-    let (client_port, client_chan) = pipes::stream();
-    let (value_port, value_chan) = pipes::stream();
-
-    do spawn {
-        loop {
-            match f() {
-                Some(value) => value_chan.send(value),
-                None => break,
-            }
-        }
-    }
-
-    let initial = match default {
-        Some(value) => value,
-        None => value_port.recv(),
-    };
+    Graph::new().dispatcher(default, f)
+}
 
-    signal_loop(initial, value_port, client_port, |x, _| x, |_| true);
+#[inline(always)]
+pub fn try_dispatcher<T: Clone Owned>(default: Option<T>, f: ~fn() -> Option<T>) -> Result<Signal<T>, ~str> {
+    Graph::new().try_dispatcher(default, f)
+}
 
-    Signal::new(client_chan)
+#[inline(always)]
+pub fn input<T: Clone Owned>(default: T) -> Input<T> {
+    Graph::new().input(default)
 }
 
+// Emits whichever input changed, preferring the first one when both changed
+// in response to the same event
 #[inline(always)]
 pub fn merge<T: Clone Owned>(one: &Signal<T>, two: &Signal<T>) -> Signal<T> {
+    merges(&[one, two])
+}
+
+#[inline(always)]
+pub fn merge2<T: Clone Owned, U: Clone Owned>(one: &Signal<T>, two: &Signal<U>) -> Signal<(T, U)> {
     let (port, chan) = pipes::stream();
 
     let (update1, client1) = pipes::stream();
     let (update2, client2) = pipes::stream();
 
-    one.add_chan(client1);
-    two.add_chan(client2);
+    let graph = one.graph();
+    let node_graph = graph.clone();
+
+    let sub1 = one.add_pulse_chan(client1, &graph);
+    let sub2 = two.add_pulse_chan(client2, &graph);
+
+    let (clock1, first1) = recv_initial(&update1);
+    let (clock2, first2) = recv_initial(&update2);
+
+    let deps = ~[one.sources(), two.sources()];
+    let sources = join_sources(deps);
 
-    do spawn {
-        let mut chans: ~[Chan<T>] = ~[];
+    let node = graph.register("merge2", [one.node(), two.node()]);
+    let trace = graph.trace(node);
+    let meter = graph.meter(node);
 
-        let _ = update2.recv();
-        let mut last = update1.recv();
+    do graph.spawn {
+        // Kept for as long as the node listens, and to count what is on its way in
+        let upstream = ~[sub1, sub2];
 
-        let mut push: bool;
+        let mut chans: ~[Subscriber<(T, U)>] = ~[];
+
+        let mut last1 = first1;
+        let mut last2 = first2;
+
+        let mut clock = join_clocks(&[copy clock1, copy clock2]);
+        let mut barrier = Barrier::new(deps, ~[clock1, clock2]);
+        let mut queue1: ~[(Stamp, Option<T>)] = ~[];
+        let mut queue2: ~[(Stamp, Option<U>)] = ~[];
 
         let mut u1_open = true;
         let mut u2_open = true;
         let mut client_open = true;
+        let mut ended = false;
 
         let header1 = PacketHeader();
         let header2 = PacketHeader();
@@ -263,40 +1387,83 @@ pub fn merge<T: Clone Owned>(one: &Signal<T>, two: &Signal<T>) -> Signal<T> {
         let mut ports = ~[update1.header(), update2.header(), port.header()];
 
         while u1_open || u2_open || client_open {
-            push = false;
+            let mut received = false;
             match selecti( ports ) {
                 0 => {
                     match update1.try_recv() {
-                        Some(value) => {
-                            last = value;
+                        Some(Change(stamp, value)) => {
+                            upstream[0].taken();
+                            trace.received(&value);
+                            queue1.push( (stamp, Some(value)) );
+                            meter.received(chans.len(), queue1.len() + queue2.len() + backlog(upstream));
+                            barrier.arrive(0, stamp);
+                            ports[0] = update1.header();
+                            received = true;
+                        }
+                        Some(NoChange(stamp)) => {
+                            upstream[0].taken();
+                            queue1.push( (stamp, None) );
+                            barrier.arrive(0, stamp);
                             ports[0] = update1.header();
-                            push = true;
+                            received = true;
                         }
+                        Some(End) => {
+                            u1_open = false;
+                            ports[0] = &header1;
+                            barrier.end(0);
+                            received = true;
+                        }
+                        Some(Initial(*)) => fail ~"merge2() - Received a second initial value",
                         None => {
                             u1_open = false;
                             ports[0] = &header1;
+                            barrier.end(0);
                         }
                     }
                 }
                 1 => {
                     match update2.try_recv() {
-                        Some(value) => {
-                            last = value;
+                        Some(Change(stamp, value)) => {
+                            upstream[1].taken();
+                            trace.received(&value);
+                            queue2.push( (stamp, Some(value)) );
+                            meter.received(chans.len(), queue1.len() + queue2.len() + backlog(upstream));
+                            barrier.arrive(1, stamp);
+                            ports[1] = update2.header();
+                            received = true;
+                        }
+                        Some(NoChange(stamp)) => {
+                            upstream[1].taken();
+                            queue2.push( (stamp, None) );
+                            barrier.arrive(1, stamp);
                             ports[1] = update2.header();
-                            push = true;
+                            received = true;
+                        }
+                        Some(End) => {
+                            u2_open = false;
+                            ports[1] = &header2;
+                            barrier.end(1);
+                            received = true;
                         }
+                        Some(Initial(*)) => fail ~"merge2() - Received a second initial value",
                         None => {
                             u2_open = false;
                             ports[1] = &header2;
+                            barrier.end(1);
                         }
                     }
                 }
                 2 => {
                     match port.try_recv() {
                         Some(ch) => {
-                            let ch: Chan<T> = ch;
-                            ch.send( last.clone() );
-                            chans.push( ch );
+                            let ch: Subscriber<(T, U)> = ch;
+                            let value = (last1.clone(), last2.clone());
+                            if ended {
+                                subscribe_ended(ch, clock, &value);
+                            } else {
+                                subscribe(&mut chans, ch, clock, &value);
+                                meter.gauges(chans.len(), queue1.len() + queue2.len() + backlog(upstream));
+                            }
                             ports[2] = port.header();
                         }
                         None => {
@@ -308,367 +1475,672 @@ pub fn merge<T: Clone Owned>(one: &Signal<T>, two: &Signal<T>) -> Signal<T> {
                 _ => fail ~"Merge incorrectly implemented",
             }
 
-            if push {
-                for chans.each |ch| {
-                    let value = last.clone();
-                    if !ch.try_send( value ) {
-                        fail ~"merge() - This need to get fixed";
+            // Emit once per event, after every input affected by it has reported
+            loop {
+                match barrier.settle() {
+                    Some(stamp) => {
+                        tick(&mut clock, stamp);
+                        let mut changed = false;
+                        match take(&mut queue1, stamp) {
+                            Some(value) => { last1 = value; changed = true; }
+                            None => (),
+                        }
+                        match take(&mut queue2, stamp) {
+                            Some(value) => { last2 = value; changed = true; }
+                            None => (),
+                        }
+                        if changed {
+                            let value = (last1.clone(), last2.clone());
+                            meter.emitted(None, chans.len(), queue1.len() + queue2.len() + backlog(upstream));
+                            trace.emitted(None, &value);
+                            broadcast(&mut chans, stamp, &value);
+                        } else {
+                            meter.filtered(None, chans.len(), queue1.len() + queue2.len() + backlog(upstream));
+                            trace.filtered(None, &(last1.clone(), last2.clone()));
+                            broadcast_unchanged(&mut chans, stamp);
+                        }
+                    }
+                    None => break,
+                }
+            }
+
+            // Ends once both inputs have
+            if !u1_open && !u2_open && !ended {
+                ended = true;
+                broadcast_end(&mut chans);
+            }
+
+            // Only done with the pulse once whatever it settled has been sent on
+            if received { node_graph.work_done() }
+
+            if !client_open && chans.is_empty() {
+                drain(&update1, &node_graph);
+                drain(&update2, &node_graph);
+                break
+            }
+        }
+    }
+
+    Signal::new(chan, sources, graph, node)
+}
+
+#[inline(always)]
+pub fn merge3<A: Clone Owned, B: Clone Owned, C: Clone Owned>(
+    one: &Signal<A>,
+    two: &Signal<B>,
+    three: &Signal<C>
+) -> Signal<(A, B, C)> {
+    // merge2 only emits once per event, so nesting it is glitch-free as well
+    let m1 = &merge2(one, two);
+    merge2(m1, three).lift(|((a, b), c)| (a, b, c))
+}
+
+// Like merges, but returns an error rather than failing on an empty slice
+pub fn try_merges<T: Clone Owned>(signals: &[&Signal<T>]) -> Result<Signal<T>, ~str> {
+    if signals.len() == 0 {
+        Err(~"No signals provided")
+    } else {
+        Ok(merges(signals))
+    }
+}
+
+#[inline(always)]
+pub fn merges<T: Clone Owned>(signals: &[&Signal<T>]) -> Signal<T> {
+    if signals.len() == 0 { fail ~"No signals provided" }
+
+    // Every input gives up its pulse, but the leftmost change wins
+    do join("merges", signals, |values| values[0].clone()) |changes, _| {
+        let mut winner = None;
+        do vec::consume(changes) |_, change| {
+            if winner.is_none() { winner = change }
+        }
+        winner
+    }
+}
+
+// Keeps the latest value of every input, and emits all of them whenever any
+// of them changes
+pub fn combine_latest<T: Clone Owned>(signals: &[&Signal<T>]) -> Signal<~[T]> {
+    // Nothing to combine, which never changes
+    if signals.len() == 0 { return constant(~[]) }
+
+    do join("combine_latest", signals, |values| values.map(|value| value.clone())) |changes, latest| {
+        if changes.all(|change| change.is_none()) {
+            None
+        } else {
+            let mut values = ~[];
+            do vec::consume(changes) |i, change| {
+                match change {
+                    Some(value) => values.push(value),
+                    None => values.push( latest[i].clone() ),
+                }
+            }
+            Some(values)
+        }
+    }
+}
+
+// A node over any number of inputs of the same type. Once a stamp has settled,
+// step gets the change every input made for it, if any, and decides whether
+// the node changes.
+fn join<T: Clone Owned, U: Clone Owned>(
+    kind: &str, signals: &[&Signal<T>], initial: &fn(&[T]) -> U, step: ~fn(~[Option<T>], &U) -> Option<U>) -> Signal<U>
+{
+    let (client_port, client_chan) = pipes::stream();
+
+    let graph = signals[0].graph();
+    let node_graph = graph.clone();
+
+    let mut subs = ~[];
+    let mut clocks = ~[];
+    let mut values = ~[];
+    let ports = do signals.map |signal| {
+        let (port, chan) = pipes::stream();
+        subs.push( signal.add_pulse_chan(chan, &graph) );
+        let (clock, value) = recv_initial(&port);
+        clocks.push(clock);
+        values.push(value);
+        port
+    };
+
+    let deps = do signals.map |signal| { signal.sources() };
+    let sources = join_sources(deps);
+    let first = initial(values);
+
+    let node = graph.register(kind, signals.map(|signal| signal.node()));
+    let trace = graph.trace(node);
+    let meter = graph.meter(node);
+
+    do graph.spawn {
+        let upstream = subs;
+
+        let mut chans: ~[Subscriber<U>] = ~[];
+
+        let mut last = first;
+
+        let mut clock = join_clocks(clocks);
+        let mut barrier = Barrier::new(deps, copy clocks);
+        let mut queues: ~[~[(Stamp, Option<T>)]] = vec::from_fn(ports.len(), |_| ~[]);
+
+        let mut open = ports.len();
+        let mut client_open = true;
+        let mut ended = false;
+
+        let closed = do vec::from_fn(ports.len() + 1) |_| { PacketHeader() };
+
+        let mut headers = do vec::from_fn(ports.len()) |i| { ports[i].header() };
+        headers.push( client_port.header() );
+
+        while open > 0 || client_open {
+            let mut received = false;
+            let i = selecti( headers );
+            if i < ports.len() {
+                match ports[i].try_recv() {
+                    Some(Change(stamp, value)) => {
+                        upstream[i].taken();
+                        trace.received(&value);
+                        queues[i].push( (stamp, Some(value)) );
+                        meter.received(chans.len(), queued(queues) + backlog(upstream));
+                        barrier.arrive(i, stamp);
+                        headers[i] = ports[i].header();
+                        received = true;
+                    }
+                    Some(NoChange(stamp)) => {
+                        upstream[i].taken();
+                        queues[i].push( (stamp, None) );
+                        barrier.arrive(i, stamp);
+                        headers[i] = ports[i].header();
+                        received = true;
+                    }
+                    Some(End) => {
+                        open -= 1;
+                        headers[i] = &closed[i];
+                        barrier.end(i);
+                        received = true;
+                    }
+                    Some(Initial(*)) => fail ~"join() - Received a second initial value",
+                    None => {
+                        open -= 1;
+                        headers[i] = &closed[i];
+                        barrier.end(i);
+                    }
+                }
+            } else {
+                match client_port.try_recv() {
+                    Some(ch) => {
+                        let ch: Subscriber<U> = ch;
+                        if ended {
+                            subscribe_ended(ch, clock, &last);
+                        } else {
+                            subscribe(&mut chans, ch, clock, &last);
+                            meter.gauges(chans.len(), queued(queues) + backlog(upstream));
+                        }
+                        headers[i] = client_port.header();
+                    }
+                    None => {
+                        client_open = false;
+                        headers[i] = &closed[i];
+                    }
+                }
+            }
+
+            loop {
+                match barrier.settle() {
+                    Some(stamp) => {
+                        tick(&mut clock, stamp);
+                        let mut changes = ~[];
+                        for uint::range(0, queues.len()) |j| {
+                            changes.push( take(&mut queues[j], stamp) );
+                        }
+                        let started = meter.start();
+                        match step(changes, &last) {
+                            Some(value) => {
+                                last = value;
+                                meter.emitted(started, chans.len(), queued(queues) + backlog(upstream));
+                                trace.emitted(None, &last);
+                                broadcast(&mut chans, stamp, &last);
+                            }
+                            None => {
+                                meter.filtered(started, chans.len(), queued(queues) + backlog(upstream));
+                                trace.filtered(None, &last);
+                                broadcast_unchanged(&mut chans, stamp);
+                            }
+                        }
                     }
+                    None => break,
                 }
             }
+
+            // Ends once every input has
+            if open == 0 && !ended {
+                ended = true;
+                broadcast_end(&mut chans);
+            }
+
+            // Only done with the pulse once whatever it settled has been sent on
+            if received { node_graph.work_done() }
+
+            if !client_open && chans.is_empty() {
+                for ports.each |port| { drain(port, &node_graph) }
+                break
+            }
         }
     }
 
-    Signal::new(chan)
+    Signal::new(client_chan, sources, graph, node)
+}
+
+#[inline(always)]
+pub fn foldp<T: Clone Owned, U: Clone Owned>(signal: &Signal<T>, default: U, f: ~fn(T, U) -> U) -> Signal<U> {
+    filter_lift_as("foldp", signal, default, |_| true, f)
+}
+
+#[inline(always)]
+pub fn foldp1<T: Clone Owned>(signal: &Signal<T>, f: ~fn(T, T) -> T) -> Signal<T> {
+    let (update, chan) = pipes::stream();
+    let (client_port, client_chan) = pipes::stream();
+
+    let graph = signal.graph();
+    let sub = signal.add_pulse_chan(chan, &graph);
+
+    let (clock, initial) = recv_initial(&update);
+
+    let node = graph.register("foldp1", [signal.node()]);
+    signal_loop(&graph, node, initial, clock, update, client_port, ~[sub], f, |_| true);
+
+    Signal::new(client_chan, signal.sources(), graph, node)
 }
 
 #[inline(always)]
-pub fn merge2<T: Clone Owned, U: Clone Owned>(one: &Signal<T>, two: &Signal<U>) -> Signal<(T, U)> {
-    let (port, chan) = pipes::stream();
+pub fn filter<T: Clone Owned>(signal: &Signal<T>, default: T, f: ~fn(&T) -> bool) -> Signal<T> {
+    filter_lift_as("filter", signal, default, f, |x, _| x)
+}
 
-    let (update1, client1) = pipes::stream();
-    let (update2, client2) = pipes::stream();
+pub fn count<T: Clone Owned>(signal: &Signal<T>) -> Signal<uint> {
+    foldp(signal, 0 as uint, |_, x| x+1)
+}
 
-    one.add_chan(client1);
-    two.add_chan(client2);
+pub fn countIf<T: Clone Owned>(signal: &Signal<T>, default: T, f: ~fn(&T)-> bool) -> Signal<uint> {
+    signal.filter(default, f).foldp(0 as uint, |_, x| x+1)
+}
 
-    do spawn {
-        let mut chans: ~[Chan<(T, U)>] = ~[];
+pub fn keepWhen<T: Clone Owned>(signal: &Signal<T>, other: &Signal<bool>, default: T) -> Signal<T> {
+    let merged = merge2(signal, other);
+    filter_lift_as("keepWhen", &merged, default, |&(_, x)| x, |(x, _), _| x)
+}
 
-        let mut last1 = update1.recv();
-        let mut last2 = update2.recv();
+// The value of a signal, but only updated when ticker changes
+pub fn sample_on<T: Clone Owned, U: Clone Owned>(ticker: &Signal<U>, signal: &Signal<T>) -> Signal<T> {
+    let merged = merge2(signal, &count(ticker));
 
-        let mut push: bool;
+    let (update, chan) = pipes::stream();
+    let (client_port, client_chan) = pipes::stream();
 
-        let mut u1_open = true;
-        let mut u2_open = true;
-        let mut client_open = true;
+    let graph = merged.graph();
+    let sub = merged.add_pulse_chan(chan, &graph);
 
-        let header1 = PacketHeader();
-        let header2 = PacketHeader();
-        let header3 = PacketHeader();
+    let (clock, first) = recv_initial(&update);
 
-        let mut ports = ~[update1.header(), update2.header(), port.header()];
+    let node = graph.register("sample_on", [merged.node()]);
+    // Remembers how often the ticker had fired at the last sample
+    do step_loop(&graph, node, first, clock, update, client_port, ~[sub]) |(x, ticks), (value, last)| {
+        if ticks != last { Emit((x, ticks)) } else { Skip((value, last)) }
+    }
 
-        while u1_open || u2_open || client_open {
-            push = false;
-            match selecti( ports ) {
-                0 => {
-                    match update1.try_recv() {
-                        Some(value) => {
-                            last1 = value;
-                            ports[0] = update1.header();
-                            push = true;
-                        }
-                        None => {
-                            u1_open = false;
-                            ports[0] = &header1;
-                        }
-                    }
-                }
-                1 => {
-                    match update2.try_recv() {
-                        Some(value) => {
-                            last2 = value;
-                            ports[1] = update2.header();
-                            push = true;
-                        }
-                        None => {
-                            u2_open = false;
-                            ports[1] = &header2;
-                        }
-                    }
-                }
-                2 => {
-                    match port.try_recv() {
-                        Some(ch) => {
-                            let ch: Chan<(T, U)> = ch;
-                            let value = (last1.clone(), last2.clone());
-                            ch.send( value );
-                            chans.push( ch );
-                            ports[2] = port.header();
-                        }
-                        None => {
-                            client_open = false;
-                            ports[2] = &header3;
-                        }
-                    }
-                }
-                _ => fail ~"Merge incorrectly implemented",
-            }
+    let sampled: Signal<(T, uint)> = Signal::new(client_chan, merged.sources(), graph, node);
+    sampled.lift(|(x, _)| x)
+}
 
-            if push {
-                for chans.each |ch| {
-                    let value = (last1.clone(), last2.clone());
-                    if !ch.try_send( value ) {
-                        fail ~"merge2() - This need to get fixed";
-                    }
-                }
-            }
-        }
+#[inline(always)]
+pub fn dropRepeats<T: Eq Clone Owned>(signal: &Signal<T>) -> Signal<T> {
+    let (update, chan) = pipes::stream();
+    let (client_port, client_chan) = pipes::stream();
+
+    let graph = signal.graph();
+    let sub = signal.add_pulse_chan(chan, &graph);
+
+    let (clock, first) = recv_initial(&update);
+
+    let node = graph.register("dropRepeats", [signal.node()]);
+    do step_loop(&graph, node, first, clock, update, client_port, ~[sub]) |x, value| {
+        if x != value { Emit(x) } else { Skip(value) }
     }
 
-    Signal::new(chan)
+    Signal::new(client_chan, signal.sources(), graph, node)
 }
 
-#[inline(always)]
-pub fn merge3<A: Clone Owned, B: Clone Owned, C: Clone Owned>(
-    one: &Signal<A>,
-    two: &Signal<B>,
-    three: &Signal<C>
-) -> Signal<(A, B, C)> {
-    let (port, chan) = pipes::stream();
+// Follows whichever signal the outer signal currently holds, unsubscribing
+// from the previous one. Updates of the inner signals are passed on as they
+// come, so this is only glitch-free with respect to the outer signal.
+pub fn switch<T: Clone Owned>(signals: &Signal<Signal<T>>) -> Signal<T> {
+    let (outer, chan) = pipes::stream();
+    let (client_port, client_chan) = pipes::stream();
 
-    let (update1, client1) = pipes::stream();
-    let (update2, client2) = pipes::stream();
-    let (update3, client3) = pipes::stream();
+    let graph = signals.graph();
+    let node_graph = graph.clone();
+    let outer_sub = signals.add_pulse_chan(chan, &graph);
 
-    one.add_chan(client1);
-    two.add_chan(client2);
-    three.add_chan(client3);
+    let (outer_clock, first) = recv_initial(&outer);
 
-    do spawn {
-        let mut chans: ~[Chan<(A, B, C)>] = ~[];
+    let (inner_port, inner_chan) = pipes::stream();
+    let inner_sub = first.add_pulse_chan(inner_chan, &graph);
+    let (inner_clock, first_value) = recv_initial(&inner_port);
 
-        let mut last1 = update1.recv();
-        let mut last2 = update2.recv();
-        let mut last3 = update3.recv();
+    // Only the outer signal is always followed
+    let node = graph.register("switch", [signals.node()]);
+    let trace = graph.trace(node);
+    let meter = graph.meter(node);
 
-        let mut push: bool;
+    do graph.spawn {
+        let mut inner_sub = inner_sub;
+        let mut inner = inner_port;
 
-        let mut u1_open = true;
-        let mut u2_open = true;
-        let mut u3_open = true;
+        let mut chans: ~[Subscriber<T>] = ~[];
+        let mut value = first_value;
+        let mut clock = join_clocks(&[outer_clock, inner_clock]);
+
+        let mut outer_open = true;
+        let mut inner_open = true;
         let mut client_open = true;
+        let mut ended = false;
 
+        let header0 = PacketHeader();
         let header1 = PacketHeader();
         let header2 = PacketHeader();
-        let header3 = PacketHeader();
-        let header4 = PacketHeader();
 
-        let mut ports = ~[update1.header(), update2.header(), update3.header(), port.header()];
+        let mut ports = ~[outer.header(), inner.header(), client_port.header()];
 
-        while u1_open || u2_open || u3_open || client_open {
-            push = false;
+        while outer_open || inner_open || client_open {
+            let mut received = false;
             match selecti( ports ) {
                 0 => {
-                    match update1.try_recv() {
-                        Some(value) => {
-                            last1 = value;
-                            ports[0] = update1.header();
-                            push = true;
+                    match outer.try_recv() {
+                        Some(Change(stamp, signal)) => {
+                            outer_sub.taken();
+                            tick(&mut clock, stamp);
+                            // Signals are traced by the id of their node
+                            meter.received(chans.len(), outer_sub.backlog() + inner_sub.backlog());
+                            let since = trace.received(&signal.node());
+
+                            // Replacing the subscription disposes of the old one
+                            let (port, chan) = pipes::stream();
+                            inner_sub = signal.add_pulse_chan(chan, &node_graph);
+
+                            let (inner_clock, v) = recv_initial(&port);
+                            for inner_clock.each |&stamp| { tick(&mut clock, stamp) }
+
+                            // The old signal may have kept sending while this
+                            // waited for the new one
+                            drain(&inner, &node_graph);
+                            inner = port;
+                            inner_open = true;
+                            ports[1] = inner.header();
+
+                            value = v;
+                            meter.emitted(None, chans.len(), outer_sub.backlog() + inner_sub.backlog());
+                            trace.emitted(since, &value);
+                            broadcast(&mut chans, stamp, &value);
+                            ports[0] = outer.header();
+                            received = true;
+                        }
+                        Some(NoChange(stamp)) => {
+                            outer_sub.taken();
+                            tick(&mut clock, stamp);
+                            broadcast_unchanged(&mut chans, stamp);
+                            ports[0] = outer.header();
+                            received = true;
                         }
+                        Some(End) => {
+                            outer_open = false;
+                            ports[0] = &header0;
+                            received = true;
+                        }
+                        Some(Initial(*)) => fail ~"switch() - Received a second initial value",
                         None => {
-                            u1_open = false;
-                            ports[0] = &header1;
+                            outer_open = false;
+                            ports[0] = &header0;
                         }
                     }
                 }
                 1 => {
-                    match update2.try_recv() {
-                        Some(value) => {
-                            last2 = value;
-                            ports[1] = update2.header();
-                            push = true;
+                    match inner.try_recv() {
+                        Some(Change(stamp, v)) => {
+                            inner_sub.taken();
+                            tick(&mut clock, stamp);
+                            meter.received(chans.len(), outer_sub.backlog() + inner_sub.backlog());
+                            let since = trace.received(&v);
+                            value = v;
+                            meter.emitted(None, chans.len(), outer_sub.backlog() + inner_sub.backlog());
+                            trace.emitted(since, &value);
+                            broadcast(&mut chans, stamp, &value);
+                            ports[1] = inner.header();
+                            received = true;
                         }
-                        None => {
-                            u2_open = false;
-                            ports[1] = &header2;
+                        Some(NoChange(stamp)) => {
+                            inner_sub.taken();
+                            tick(&mut clock, stamp);
+                            broadcast_unchanged(&mut chans, stamp);
+                            ports[1] = inner.header();
+                            received = true;
                         }
-                    }
-                }
-                2 => {
-                    match update3.try_recv() {
-                        Some(value) => {
-                            last3 = value;
-                            ports[2] = update3.header();
-                            push = true;
+                        Some(End) => {
+                            inner_open = false;
+                            ports[1] = &header1;
+                            received = true;
                         }
+                        Some(Initial(*)) => fail ~"switch() - Received a second initial value",
                         None => {
-                            u3_open = false;
-                            ports[2] = &header3;
+                            inner_open = false;
+                            ports[1] = &header1;
                         }
                     }
                 }
-                3 => {
-                    match port.try_recv() {
+                2 => {
+                    match client_port.try_recv() {
                         Some(ch) => {
-                            let ch: Chan<(A, B, C)> = ch;
-                            let value = (last1.clone(), last2.clone(), last3.clone());
-                            ch.send( value );
-                            chans.push( ch );
-                            ports[3] = port.header();
+                            let ch: Subscriber<T> = ch;
+                            if ended {
+                                subscribe_ended(ch, clock, &value);
+                            } else {
+                                subscribe(&mut chans, ch, clock, &value);
+                                meter.gauges(chans.len(), outer_sub.backlog() + inner_sub.backlog());
+                            }
+                            ports[2] = client_port.header();
                         }
                         None => {
                             client_open = false;
-                            ports[3] = &header4;
+                            ports[2] = &header2;
                         }
                     }
                 }
-                _ => fail ~"Merge incorrectly implemented",
+                _ => fail ~"switch incorrectly implemented",
             }
 
-            if push {
-                for chans.each |ch| {
-                    let value = (last1.clone(), last2.clone(), last3.clone());
-                    if !ch.try_send( value ) {
-                        fail ~"merge3() - This need to get fixed";
-                    }
-                }
+            if received { node_graph.work_done() }
+
+            // Ends once the outer signal and the last inner one have
+            if !outer_open && !inner_open && !ended {
+                ended = true;
+                broadcast_end(&mut chans);
+            }
+
+            if !client_open && chans.is_empty() {
+                drain(&outer, &node_graph);
+                drain(&inner, &node_graph);
+                break
             }
         }
     }
 
-    Signal::new(chan)
+    Signal::new(client_chan, signals.sources(), graph, node)
 }
 
-#[inline(always)]
-pub fn merges<T: Clone Owned>(signals: &[&Signal<T>]) -> Signal<T> {
-    if signals.len() == 0 { fail ~"No signals provided" }    
+pub fn flat_map<T: Clone Owned, U: Clone Owned>(signal: &Signal<T>, f: ~fn(T) -> Signal<U>) -> Signal<U> {
+    switch(&signal.lift(f))
+}
 
+// Lets the first n updates through, then ends
+pub fn take<T: Clone Owned>(signal: &Signal<T>, n: uint) -> Signal<T> {
+    let (update, chan) = pipes::stream();
     let (client_port, client_chan) = pipes::stream();
-    let (merged_port, merged_chan) = pipes::stream();
 
-    let mut value = None;
-    let ports = do signals.map |signal| {
-        let (port, chan) = pipes::stream();
-        signal.add_chan(chan);
-        value = port.try_recv();
-        port
-    };
+    let graph = signal.graph();
+    let sub = signal.add_pulse_chan(chan, &graph);
 
-    let port_set = PortSet { ports: ports };
+    let (clock, first) = recv_initial(&update);
 
-    do spawn {
-        loop {
-            match port_set.try_recv() {
-                Some(value) => merged_chan.send(value),
-                None => break,
-            }
-        }
+    if n == 0 {
+        // Nothing will be read from the input, but what it already sent counts
+        drain(&update, &graph);
+        return graph.constant(first)
     }
 
-    let value = match value {
-        Some(v) => v,
-        None => fail ~"No active signals provided",
-    };
-
-    signal_loop(value, merged_port, client_port, |x, _| x, |_| true);
+    let node = graph.register("take", [signal.node()]);
+    // Counts the updates let through alongside the value
+    do step_loop(&graph, node, (0 as uint, first), clock, update, client_port, ~[sub]) |x, (count, _)| {
+        if count + 1 < n { Emit((count + 1, x)) } else { EmitLast((count + 1, x)) }
+    }
 
-    Signal::new(client_chan)
+    let counted: Signal<(uint, T)> = Signal::new(client_chan, signal.sources(), graph, node);
+    counted.lift(|(_, x)| x)
 }
 
-#[inline(always)]
-pub fn foldp<T: Clone Owned, U: Clone Owned>(signal: &Signal<T>, default: U, f: ~fn(T, U) -> U) -> Signal<U> {
+// Emits the updates in batches of n. Starts out as an empty batch, and a
+// partial batch is dropped when the signal ends.
+pub fn buffer_count<T: Clone Owned>(signal: &Signal<T>, n: uint) -> Signal<~[T]> {
+    if n == 0 { fail ~"buffer_count() needs batches of at least one update" }
+
     let (update, chan) = pipes::stream();
     let (client_port, client_chan) = pipes::stream();
 
-    signal.add_chan(chan);
+    let graph = signal.graph();
+    let sub = signal.add_pulse_chan(chan, &graph);
+
+    let (clock, _) = recv_initial(&update);
 
-    signal_loop(default, update, client_port, f, |_| true);
+    let node = graph.register("buffer_count", [signal.node()]);
+    // The batch being filled stays in the node, only full ones go out
+    let pending: ~[T] = ~[];
+    do state_loop(&graph, node, ~[], pending, clock, update, client_port, ~[sub]) |x, batch, pending| {
+        pending.push(x);
+        if pending.len() == n { Emit( util::replace(pending, ~[]) ) } else { Skip(batch) }
+    }
 
-    Signal::new(client_chan)
+    Signal::new(client_chan, signal.sources(), graph, node)
 }
 
-#[inline(always)]
-pub fn foldp1<T: Clone Owned>(signal: &Signal<T>, f: ~fn(T, T) -> T) -> Signal<T> {
+// Emits the last n updates every step updates, once n have come in
+pub fn window<T: Clone Owned>(signal: &Signal<T>, n: uint, step: uint) -> Signal<~[T]> {
+    if n == 0 || step == 0 { fail ~"window() needs a size and a step of at least one update" }
+
     let (update, chan) = pipes::stream();
     let (client_port, client_chan) = pipes::stream();
 
-    signal.add_chan(chan);
-
-    let initial = update.recv();
-
-    signal_loop(initial, update, client_port, f, |_| true);
+    let graph = signal.graph();
+    let sub = signal.add_pulse_chan(chan, &graph);
+
+    let (clock, _) = recv_initial(&update);
+
+    // The last n updates, and how many came in since the last window
+    let recent: (~[T], uint) = (~[], 0);
+    let node = graph.register("window", [signal.node()]);
+    do state_loop(&graph, node, ~[], recent, clock, update, client_port, ~[sub]) |x, batch, state| {
+        let (ref mut recent, ref mut since) = *state;
+        recent.push(x);
+        if recent.len() > n { vec::shift(recent); }
+        *since += 1;
+
+        if recent.len() == n && *since >= step {
+            *since = 0;
+            Emit( recent.map(|x| x.clone()) )
+        } else {
+            Skip(batch)
+        }
+    }
 
-    Signal::new(client_chan)
+    Signal::new(client_chan, signal.sources(), graph, node)
 }
 
-#[inline(always)]
-pub fn filter<T: Clone Owned>(signal: &Signal<T>, default: T, f: ~fn(&T) -> bool) -> Signal<T> {
+// Follows a signal until another one changes, then ends
+pub fn take_until<T: Clone Owned, U: Clone Owned>(signal: &Signal<T>, other: &Signal<U>) -> Signal<T> {
+    let merged = merge2(signal, &count(other));
+
     let (update, chan) = pipes::stream();
     let (client_port, client_chan) = pipes::stream();
 
-    signal.add_chan(chan);
-
-    signal_loop(default, update, client_port, |x, _| x, f);
-
-    Signal::new(client_chan)
-}
+    let graph = merged.graph();
+    let sub = merged.add_pulse_chan(chan, &graph);
 
-pub fn count<T: Clone Owned>(signal: &Signal<T>) -> Signal<uint> {
-    foldp(signal, 0 as uint, |_, x| x+1)
-}
+    let (clock, (first, start)) = recv_initial(&update);
 
-pub fn countIf<T: Clone Owned>(signal: &Signal<T>, default: T, f: ~fn(&T)-> bool) -> Signal<uint> {
-    signal.filter(default, f).foldp(0 as uint, |_, x| x+1)
-}
+    let node = graph.register("take_until", [merged.node()]);
+    do step_loop(&graph, node, first, clock, update, client_port, ~[sub]) |(x, fired), value| {
+        if fired != start { Stop(value) } else { Emit(x) }
+    }
 
-pub fn keepWhen<T: Clone Owned>(signal: &Signal<T>, other: &Signal<bool>, default: T) -> Signal<T> {
-    let merged = merge2(signal, other);
-    filter_lift(&merged, default, |&(_, x)| x, |(x, _), _| x)
+    Signal::new(client_chan, merged.sources(), graph, node)
 }
 
-// TODO: implementing this with a more generic function (a filter-fold?) would be good
-#[inline(always)]
-pub fn dropRepeats<T: Eq Clone Owned>(signal: &Signal<T>) -> Signal<T> {
+// Calls f once the signal ends. Disposing of the subscription cancels it.
+pub fn on_complete<T: Clone Owned>(signal: &Signal<T>, f: ~fn()) -> Subscription {
     let (update, chan) = pipes::stream();
-    let (client_port, client_chan) = pipes::stream();
 
-    signal.add_chan(chan);
+    let graph = signal.graph();
+    let node_graph = graph.clone();
+    let sub = signal.add_pulse_chan(chan, &graph);
 
-    do spawn {
-        let mut value = update.recv();
+    do graph.spawn {
+        loop {
+            match update.try_recv() {
+                Some(End) => {
+                    f();
+                    node_graph.work_done();
+                    break
+                }
+                Some(Initial(*)) => (),
+                Some(_) => node_graph.work_done(),
+                // Disposed of before the signal ended
+                None => break,
+            }
+        }
+    }
 
-        let header0 = PacketHeader();
-        let header1 = PacketHeader();
+    sub
+}
 
-        let mut client_open = true;
-        let mut update_open = true;
+// Receives the final value of a signal once it ends
+pub fn last<T: Clone Owned>(signal: &Signal<T>) -> Port<T> {
+    let (port, result) = pipes::stream();
+    let (update, chan) = pipes::stream();
 
-        let mut chans: ~[Chan<T>] = ~[];
+    let graph = signal.graph();
+    let node_graph = graph.clone();
+    let sub = signal.add_pulse_chan(chan, &graph);
 
-        let mut ports = ~[update.header(), client_port.header()];
+    let (_, first) = recv_initial(&update);
 
-        while client_open || update_open {
-            match selecti(ports) {
-                0 => {
-                    match update.try_recv() {
-                        Some(v) => {
-                            if v != value {
-                                value = v;
-                                for chans.each |ch| {
-                                    ch.send( value.clone() );
-                                }
-                            }
-                            ports[0] = update.header();
-                        }
-                        None => {
-                            update_open = false;
-                            ports[0] = &header0;
-                        }
-                    }
+    do graph.spawn {
+        let _upstream = sub;
+        let mut value = first;
+
+        loop {
+            match update.try_recv() {
+                Some(Change(_, v)) => {
+                    value = v;
+                    node_graph.work_done();
                 }
-                1 => {
-                    match client_port.try_recv() {
-                        Some(ch) => {
-                            let ch: Chan<T> = ch;
-                            ch.send( value.clone() );
-                            chans.push(ch);
-                            ports[1] = client_port.header();
-                        }
-                        None => {
-                            client_open = false;
-                            ports[1] = &header1;
-                        }
-                    }
+                Some(End) => {
+                    result.send( value.clone() );
+                    node_graph.work_done();
+                    break
                 }
-                _ => fail ~"dropRepeats incorrectly implemented",
+                Some(_) => node_graph.work_done(),
+                None => break,
             }
         }
     }
 
-    Signal::new(client_chan)
+    port
 }
 
 pub fn split<T: Clone Owned, U: Clone Owned>(signal: &Signal<Either<T, U>>, left: T, right: U) -> (Signal<T>, Signal<U>) {
@@ -677,3 +2149,185 @@ pub fn split<T: Clone Owned, U: Clone Owned>(signal: &Signal<Either<T, U>>, left
     (left, right)
 }
 
+// Signals of Results carry errors downstream as values, so a failing step
+// does not take its node (and everything after it) down with it.
+
+pub fn lift_ok<T: Clone Owned, U: Clone Owned, E: Clone Owned>(signal: &Signal<Result<T, E>>, f: ~fn(T) -> U) -> Signal<Result<U, E>> {
+    do signal.lift |result| {
+        match result {
+            Ok(value) => Ok(f(value)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub fn and_then<T: Clone Owned, U: Clone Owned, E: Clone Owned>(signal: &Signal<Result<T, E>>, f: ~fn(T) -> Result<U, E>) -> Signal<Result<U, E>> {
+    do signal.lift |result| {
+        match result {
+            Ok(value) => f(value),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub fn map_err<T: Clone Owned, E: Clone Owned, F: Clone Owned>(signal: &Signal<Result<T, E>>, f: ~fn(E) -> F) -> Signal<Result<T, F>> {
+    do signal.lift |result| {
+        match result {
+            Ok(value) => Ok(value),
+            Err(e) => Err(f(e)),
+        }
+    }
+}
+
+// Replaces every error with a value computed from it
+pub fn catch_error<T: Clone Owned, E: Clone Owned>(signal: &Signal<Result<T, E>>, f: ~fn(E) -> T) -> Signal<T> {
+    do signal.lift |result| {
+        match result {
+            Ok(value) => value,
+            Err(e) => f(e),
+        }
+    }
+}
+
+// Like and_then, but calls f up to attempts times until it succeeds
+pub fn retry<T: Clone Owned, U: Clone Owned, E: Clone Owned>(signal: &Signal<Result<T, E>>, attempts: uint, f: ~fn(T) -> Result<U, E>) -> Signal<Result<U, E>> {
+    if attempts == 0 { fail ~"retry() needs at least one attempt" }
+
+    do signal.lift |result| {
+        match result {
+            Ok(value) => {
+                let mut result = f(value.clone());
+                let mut tries = 1;
+                while result.is_err() && tries < attempts {
+                    result = f(value.clone());
+                    tries += 1;
+                }
+                result
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_empty_dispatcher() {
+        let f: ~fn() -> Option<int> = || None;
+        assert try_dispatcher(None, f).is_err();
+
+        // With a default there is a value to end on
+        let ended = dispatcher(Some(3), || None);
+        assert last(&ended).recv() == 3;
+    }
+
+    #[test]
+    fn test_combine_latest_of_nothing() {
+        let empty: &[&Signal<int>] = [];
+        let (port, chan) = pipes::stream();
+        let _sub = combine_latest(empty).add_chan(chan);
+        assert port.recv().is_empty();
+    }
+
+    #[test]
+    fn test_buffer_count() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let batches = buffer_count(&marble::source(&graph, "a-b-c-d-e-f-g|"), 3);
+        // Drawn as the last update of each batch, '0' being no batch yet
+        let lasts = batches.lift(|batch| if batch.is_empty() { '0' } else { batch[batch.len() - 1] });
+        marble::expect(&clock, &lasts, "0-----d-----g|");
+    }
+
+    #[test]
+    fn test_window() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let windows = window(&marble::source(&graph, "a-b-c-d-e-f-"), 3, 2);
+        let firsts = windows.lift(|batch| if batch.is_empty() { '0' } else { batch[0] });
+        marble::expect(&clock, &firsts, "0-----b---d-");
+    }
+
+    #[test]
+    fn test_switch() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let one = marble::source(&graph, "1-2---3-4-");
+        let two = marble::source(&graph, "x--y---z--");
+        let outer = marble::source(&graph, "a----b----");
+
+        // Picks up the value two has when switched to, and never hears from
+        // one again
+        let followed = switch(&outer.lift(|x| if x == 'a' { one.clone() } else { two.clone() }));
+        marble::expect(&clock, &followed, "1-2--y-z--");
+    }
+
+    #[test]
+    fn test_current_leaves_no_subscribers() {
+        let metrics = Metrics::new();
+        let (values, chan) = pipes::stream();
+        let numbers = Graph::new().with_metrics(&metrics).dispatcher(Some(1), || values.try_recv());
+        let doubled = numbers.lift(|x| x * 2);
+
+        // The signal never changes, so only subscribing can clear out the
+        // subscribers the reads leave behind
+        for uint::range(0, 100) |_| {
+            assert doubled.current() == 2;
+        }
+
+        let mut latest = doubled.add_latest();
+        latest.next();
+        chan.send(2);
+        assert latest.next() == Some(4);
+
+        let mut found = false;
+        for metrics.snapshot().each |stats| {
+            if stats.node == doubled.node() {
+                assert stats.subscribers == 1;
+                found = true;
+            }
+        }
+        assert found;
+    }
+
+    #[test]
+    fn test_input() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let numbers = graph.input(1);
+        let total = foldp(&numbers.signal(), 0, |x, sum| x + sum);
+
+        // Sent values count as work until they have gone through
+        numbers.send(2);
+        numbers.send(3);
+        clock.run_until_idle();
+        assert total.current() == 6;
+    }
+
+    #[test]
+    fn test_input_from_other_tasks() {
+        let numbers = input(0);
+        let mut latest = numbers.signal().add_latest();
+        assert latest.next() == Some(0);
+
+        let sender = numbers.clone();
+        do task::spawn {
+            sender.send(5);
+        }
+        assert latest.next() == Some(5);
+    }
+
+    #[test]
+    fn test_input_ends_when_dropped() {
+        let numbers = input(1);
+        let ended = last(&numbers.signal());
+
+        numbers.send(2);
+        util::ignore(numbers);
+        assert ended.recv() == 2;
+    }
+}
@@ -1,34 +1,317 @@
 extern mod std;
 
-use std::time;
+use either::{ Either, Left, Right };
 use std::time::Tm;
-use std::timer;
-use std::uv;
+use clock::{ Clock, Instant };
 
-fn sleep(ms: uint) {
-    let iotask = uv::global_loop::get();
-    timer::sleep(iotask, ms);
+pub fn every(ms: uint) -> Signal<Tm> {
+    every_in(&Graph::new(), ms)
 }
 
-pub fn every(ms: uint) -> Signal<Tm> {
-    let initial = time::now();
-    do dispatcher(Some(initial)) {
-        sleep(ms);
-        Some(time::now())
+// Like every, but built against a graph, and so against its clock
+pub fn every_in(graph: &Graph, ms: uint) -> Signal<Tm> {
+    if ms == 0 { fail ~"every() needs a period of at least 1ms" }
+
+    let clock = graph.clock();
+    let start = clock.instant();
+    let initial = clock.now();
+    do graph.dispatcher(Some(initial)) {
+        // Ticks fall on multiples of ms since the start, so time spent
+        // elsewhere does not add up. Ticks that were missed are skipped.
+        // Setting the system time does not move them.
+        let now = clock.instant().ms_since(&start);
+        let next = (now / (ms as u64) + 1) * (ms as u64);
+        clock.sleep((next - now) as uint);
+        Some(clock.now())
+    }
+}
+
+// Emits n times a second, with the milliseconds since the last frame
+pub fn fps(n: uint) -> Signal<uint> {
+    fps_in(&Graph::new(), n)
+}
+
+pub fn fps_in(graph: &Graph, n: uint) -> Signal<uint> {
+    fps_when(n, &graph.constant(true))
+}
+
+// Like fps, but paused while the gate is false. The first frame after
+// resuming does not count the time spent paused.
+pub fn fps_when(n: uint, gate: &Signal<bool>) -> Signal<uint> {
+    if n == 0 || n > 1000 { fail ~"fps() needs between 1 and 1000 frames a second" }
+
+    let n = n as u64;
+    let clock = gate.graph().clock();
+    let start_clock = clock.clone();
+    let origin = clock.instant();
+
+    // Whether frames are running, when the last one was, when they started
+    // and which frame is next, as ms since origin. Frame k is due k * 1000 / n
+    // ms after the start, so periods that are not a whole number of ms do not
+    // drift.
+    let state = (false, 0u64, 0u64, 0u64);
+    let initial = |&on: &bool, state: &mut (bool, u64, u64, u64)| {
+        (0, if on { start_frames(&start_clock, &origin, n, state) } else { Keep })
+    };
+
+    do timed_loop("fps_when", gate, state, initial) |event, state, emits| {
+        let (running, last, start, frame) = *state;
+        match event {
+            Input(true) => if running { Keep } else { start_frames(&clock, &origin, n, state) },
+            Input(false) => {
+                *state = (false, last, start, frame);
+                Cancel
+            }
+            Alarm => {
+                let now = clock.instant().ms_since(&origin);
+                emits.push( (now - last) as uint );
+
+                let mut next = frame + 1;
+                while start + next * 1000 / n <= now { next += 1 }
+                *state = (true, now, start, next);
+                After((start + next * 1000 / n - now) as uint)
+            }
+            // A gate that ended open stays open
+            Finished => if running { Keep } else { Cancel },
+        }
     }
 }
 
+// Shifts every update ms later, without holding the ones behind it up
 pub fn delay<T: Clone Owned>(signal: &Signal<T>, ms: uint) -> Signal<T> {
-    do signal.lift |val| {
-        sleep(ms);
-        val
+    let clock = signal.graph().clock();
+    let origin = clock.instant();
+
+    // Updates waiting to go out, with when they are due in ms since origin
+    let queue: ~[(u64, T)] = ~[];
+    do timed_loop("delay", signal, queue, |x, _| (x.clone(), Keep)) |event, queue, emits| {
+        match event {
+            Input(x) => {
+                queue.push( (clock.instant().ms_since(&origin) + (ms as u64), x) );
+                if queue.len() == 1 { After(ms) } else { Keep }
+            }
+            Alarm => {
+                let now = clock.instant().ms_since(&origin);
+                while !queue.is_empty() && due(&queue[0]) <= now {
+                    let (_, x) = vec::shift(queue);
+                    emits.push(x);
+                }
+                if queue.is_empty() {
+                    Cancel
+                } else {
+                    After((due(&queue[0]) - now) as uint)
+                }
+            }
+            // Ends once the last update has gone out
+            Finished => if queue.is_empty() { Cancel } else { Keep },
+        }
     }
 }
 
+fn start_frames(clock: &Clock, origin: &Instant, n: u64, state: &mut (bool, u64, u64, u64)) -> Timer {
+    let now = clock.instant().ms_since(origin);
+    *state = (true, now, now, 1);
+    After((1000 / n) as uint)
+}
+
+fn due<T>(entry: &(u64, T)) -> u64 {
+    match *entry { (at, _) => at }
+}
+
 pub fn timestamp<T: Clone Owned>(signal: &Signal<T>) -> Signal<(Tm, T)> {
-    signal.lift(|val| (time::now(), val) )
+    let clock = signal.graph().clock();
+    signal.lift(|val| (clock.now(), val) )
 }
 
 pub fn timeOf<T: Clone Owned>(signal: &Signal<T>) -> Signal<Tm> {
-    signal.lift(|_| time::now() )
+    let clock = signal.graph().clock();
+    signal.lift(|_| clock.now() )
+}
+
+// Like timestamp, but with a monotonic instant, for measuring latencies
+pub fn timestamp_instant<T: Clone Owned>(signal: &Signal<T>) -> Signal<(Instant, T)> {
+    let clock = signal.graph().clock();
+    signal.lift(|val| (clock.instant(), val) )
+}
+
+pub fn instant_of<T: Clone Owned>(signal: &Signal<T>) -> Signal<Instant> {
+    let clock = signal.graph().clock();
+    signal.lift(|_| clock.instant() )
+}
+
+// True for ms after every update, false otherwise
+pub fn since<T: Clone Owned>(signal: &Signal<T>, ms: uint) -> Signal<bool> {
+    do timed_loop("since", signal, false, |_, _| (false, Keep)) |event, on, emits| {
+        match event {
+            Input(_) => {
+                if !*on {
+                    *on = true;
+                    emits.push(true);
+                }
+                After(ms)
+            }
+            Alarm => {
+                *on = false;
+                emits.push(false);
+                Cancel
+            }
+            // The last update still gets its ms
+            Finished => if *on { Keep } else { Cancel },
+        }
+    }
+}
+
+// Emits the latest value once the signal has been quiet for ms
+pub fn debounce<T: Clone Owned>(signal: &Signal<T>, ms: uint) -> Signal<T> {
+    do timed_loop("debounce", signal, None, |x, _| (x.clone(), Keep)) |event, pending, emits| {
+        match event {
+            Input(x) => {
+                *pending = Some(x);
+                After(ms)
+            }
+            // Whatever was waiting goes out right away once the signal ends
+            Alarm | Finished => {
+                match util::replace(pending, None) {
+                    Some(x) => emits.push(x),
+                    None => (),
+                }
+                Cancel
+            }
+        }
+    }
+}
+
+// Which end of a throttling window lets a value through
+pub enum Edge {
+    Leading,
+    Trailing,
+    Both,
+}
+
+// Emits at most once every ms, passing the first value of a burst on right
+// away and the latest one once the window closes
+pub fn throttle<T: Clone Owned>(signal: &Signal<T>, ms: uint) -> Signal<T> {
+    throttle_edge(signal, ms, Both)
+}
+
+pub fn throttle_edge<T: Clone Owned>(signal: &Signal<T>, ms: uint, edge: Edge) -> Signal<T> {
+    let leading = match edge { Trailing => false, _ => true };
+    let trailing = match edge { Leading => false, _ => true };
+
+    // Whether a window is open, and the value waiting for it to close
+    do timed_loop("throttle", signal, (false, None), |x, _| (x.clone(), Keep)) |event, state, emits| {
+        let (open, pending) = util::replace(state, (false, None));
+        let (next, timer) = match event {
+            Input(x) => {
+                if open {
+                    (if trailing { Some(x) } else { pending }, Keep)
+                } else if leading {
+                    emits.push(x);
+                    (None, After(ms))
+                } else {
+                    (Some(x), After(ms))
+                }
+            }
+            // A value sent at the end of a window opens the next one
+            Alarm => match pending {
+                Some(x) => {
+                    emits.push(x);
+                    (None, After(ms))
+                }
+                None => (None, Cancel),
+            },
+            Finished => {
+                match pending {
+                    Some(x) => emits.push(x),
+                    None => (),
+                }
+                (None, Cancel)
+            }
+        };
+        *state = (match timer { Cancel => false, _ => true }, next);
+        timer
+    }
+}
+
+// What timeout emits when its input went quiet for too long
+pub struct Timeout {
+    ms: uint,
+}
+
+// Passes updates on as Left, and emits a Right once no update has come for ms.
+// Every update starts the wait over.
+pub fn timeout<T: Clone Owned>(signal: &Signal<T>, ms: uint) -> Signal<Either<T, Timeout>> {
+    do timed_loop("timeout", signal, (), |x, _| (Left(x.clone()), After(ms))) |event, _, emits| {
+        match event {
+            Input(x) => {
+                emits.push( Left(x) );
+                After(ms)
+            }
+            Alarm => {
+                emits.push( Right(Timeout { ms: ms }) );
+                Cancel
+            }
+            // A signal that has ended is not stale
+            Finished => Cancel,
+        }
+    }
+}
+
+// Emits the updates that came in during each ms, including none
+pub fn buffer_time<T: Clone Owned>(signal: &Signal<T>, ms: uint) -> Signal<~[T]> {
+    let pending: ~[T] = ~[];
+    do timed_loop("buffer_time", signal, pending, |_, _| (~[], After(ms))) |event, pending, emits| {
+        match event {
+            Input(x) => {
+                pending.push(x);
+                Keep
+            }
+            Alarm => {
+                emits.push( util::replace(pending, ~[]) );
+                After(ms)
+            }
+            // What came in since the last batch still goes out
+            Finished => {
+                if !pending.is_empty() { emits.push( util::replace(pending, ~[]) ) }
+                Cancel
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_delay() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        // The current value is not delayed, only the updates after it
+        let input = marble::source(&graph, "a-b-c---");
+        marble::expect(&clock, &delay(&input, 2 * marble::FRAME), "a---b-c-");
+    }
+
+    #[test]
+    fn test_delay_does_not_hold_up() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let input = marble::source(&graph, "a(bc)d----");
+        marble::expect(&clock, &delay(&input, 3 * marble::FRAME), "a---(bc)d-");
+    }
+
+    #[test]
+    fn test_fps_does_not_drift() {
+        let clock = VirtualClock::new();
+        let graph = Graph::virtual_time(&clock);
+
+        let frames = count(&fps_in(&graph, 60));
+        clock.advance_by(1000);
+        clock.run_until_idle();
+
+        // count takes the current value as the first
+        let (port, chan) = pipes::stream();
+        let _sub = frames.add_chan(chan);
+        assert port.recv() == 61;
+    }
 }
@@ -0,0 +1,207 @@
+// Tracing the values that go through the nodes of a graph. A tracer passes
+// every traced event to a hook, on a task of its own so the hook never holds
+// a node up.
+
+extern mod std;
+
+use send_map::linear::LinearMap;
+use private::{ Exclusive, exclusive };
+use private::global::global_data_clone_create;
+use clock::{ Clock, Instant };
+
+pub enum Action {
+    Received,
+    Emitted,
+    // An update that did not change the node
+    Filtered,
+}
+
+pub struct TraceEvent {
+    node: uint,
+    // The name given with Signal::named, or else the kind of node
+    name: ~str,
+    action: Action,
+    // The value as printed by %?
+    value: ~str,
+    // When it happened, on the graph's clock
+    at_ms: u64,
+    // For emitted and filtered values, how long the node took since it
+    // received the update, including the time spent in its function
+    elapsed_ns: Option<u64>,
+}
+
+enum Request {
+    Node(uint, ~str),
+    Name(uint, ~str),
+    Event(uint, Action, ~str, u64, Option<u64>),
+}
+
+pub struct Tracer {
+    priv requests: SharedChan<Request>,
+}
+
+impl Tracer {
+    static fn new(hook: ~fn(&TraceEvent)) -> Tracer {
+        let (port, chan) = pipes::stream();
+
+        do task::spawn {
+            let mut names = LinearMap();
+            loop {
+                match port.try_recv() {
+                    Some(Node(id, kind)) => { names.insert(id, kind); }
+                    Some(Name(id, name)) => { names.insert(id, name); }
+                    Some(Event(id, action, value, at_ms, elapsed_ns)) => {
+                        let name = match names.find(&id) {
+                            Some(name) => copy *name,
+                            None => ~"?",
+                        };
+                        hook(&TraceEvent {
+                            node: id,
+                            name: name,
+                            action: action,
+                            value: value,
+                            at_ms: at_ms,
+                            elapsed_ns: elapsed_ns,
+                        });
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        Tracer { requests: SharedChan(chan) }
+    }
+
+    fn node(&self, id: uint, kind: &str) {
+        self.requests.send( Node(id, str::from_slice(kind)) );
+    }
+
+    fn name(&self, id: uint, name: &str) {
+        self.requests.send( Name(id, str::from_slice(name)) );
+    }
+
+    // Makes this the tracer of every graph created from now on, including
+    // the ones that constant, dispatcher, every and the like create for
+    // themselves
+    fn install(&self) {
+        set_global( Some(self.clone()) );
+    }
+}
+
+// Key of the tracer that new graphs start out with
+fn global_key(_: Exclusive<Option<Tracer>>) {}
+
+fn global_tracer() -> Exclusive<Option<Tracer>> {
+    unsafe { global_data_clone_create(global_key, || ~exclusive(None)) }
+}
+
+// Replaces the tracer new graphs start out with, None to stop tracing them.
+// Graphs that already exist keep theirs.
+pub fn set_global(tracer: Option<Tracer>) {
+    let mut tracer = tracer;
+    unsafe {
+        do global_tracer().with |global| {
+            *global = util::replace(&mut tracer, None);
+        }
+    }
+}
+
+pub fn global() -> Option<Tracer> {
+    unsafe {
+        do global_tracer().with |global| {
+            match *global {
+                Some(ref tracer) => Some(tracer.clone()),
+                None => None,
+            }
+        }
+    }
+}
+
+impl Tracer: Clone {
+    fn clone(&self) -> Tracer {
+        Tracer { requests: self.requests.clone() }
+    }
+}
+
+impl Tracer: Owned;
+
+// What a node traces through, which does nothing unless its graph has a tracer
+pub struct NodeTrace {
+    priv tracer: Option<Tracer>,
+    priv node: uint,
+    priv clock: Clock,
+}
+
+impl NodeTrace {
+    static fn new(tracer: Option<Tracer>, node: uint, clock: Clock) -> NodeTrace {
+        NodeTrace { tracer: tracer, node: node, clock: clock }
+    }
+
+    // Returns when the value came in, to time the node by
+    fn received<T>(&self, value: &T) -> Option<Instant> {
+        match self.tracer {
+            Some(_) => {
+                let now = self.clock.instant();
+                self.send(Received, value, None);
+                Some(now)
+            }
+            None => None,
+        }
+    }
+
+    fn emitted<T>(&self, since: Option<Instant>, value: &T) {
+        self.send(Emitted, value, self.elapsed(since));
+    }
+
+    fn filtered<T>(&self, since: Option<Instant>, value: &T) {
+        self.send(Filtered, value, self.elapsed(since));
+    }
+
+    priv fn elapsed(&self, since: Option<Instant>) -> Option<u64> {
+        match since {
+            Some(since) => Some( self.clock.instant().ns_since(&since) ),
+            None => None,
+        }
+    }
+
+    priv fn send<T>(&self, action: Action, value: &T, elapsed_ns: Option<u64>) {
+        match self.tracer {
+            Some(ref tracer) => {
+                let event = Event(self.node, action, fmt!("%?", *value), self.clock.now_ms(), elapsed_ns);
+                tracer.requests.send(event);
+            }
+            None => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_installed_tracer() {
+        let (port, chan) = pipes::stream();
+        let chan = SharedChan(chan);
+        let tracer = do Tracer::new |event| {
+            chan.send( (event.node, event.action, copy event.value) );
+        };
+
+        // dispatcher builds a graph of its own, which picks up the tracer
+        let (values, numbers) = pipes::stream();
+        tracer.install();
+        let source = dispatcher(Some(1), || values.try_recv());
+        set_global(None);
+
+        let doubled = source.lift(|x| x * 2);
+        numbers.send(2);
+
+        loop {
+            match port.recv() {
+                (node, Emitted, value) if node == doubled.node() => {
+                    assert value == ~"4";
+                    break
+                }
+                _ => (),
+            }
+        }
+    }
+}